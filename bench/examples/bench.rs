@@ -4,10 +4,13 @@ use bench::scalar_quickheap::Search;
 use clap::Parser;
 #[cfg(feature = "avx512")]
 use quickheap::Avx512;
-use quickheap::ConfigurableSimdQuickHeap;
-use quickheap::pivot_strategies::{MedianOfM, RandomPivot};
+use quickheap::bucket_alloc::HugePages;
+use quickheap::pivot_strategies::{MedianOfM, RadixPivot, RandomPivot};
+use quickheap::rebalancing_strategies::NoRebalancing;
+use quickheap::splitter_strategies::BinarySplit;
 #[cfg(feature = "avx2")]
 use quickheap::{Avx2, SimdElem};
+use quickheap::{CompactSimdQuickHeap, ConfigurableSimdQuickHeap};
 
 #[allow(unused)]
 #[cfg(feature = "perf")]
@@ -41,6 +44,15 @@ struct Result {
     hw_cache_misses: f64,
     hw_cache_references: f64,
     l3_cache_misses: f64,
+    dtlb_misses: f64,
+}
+
+/// Counts dTLB read misses, to measure the effect of huge pages.
+#[cfg(feature = "perf")]
+fn dtlb_miss_counter() -> perfcnt::linux::PerfCounter {
+    PerfCounterBuilderLinux::from_cache_event(CacheId::DTLB, CacheOpId::Read, CacheOpResultId::Miss)
+        .finish()
+        .expect("Could not initialize perfcnt. Run:\necho '1' | sudo tee /proc/sys/kernel/perf_event_paranoid\n")
 }
 
 static ARGS: LazyLock<Args> = LazyLock::new(|| Args::parse());
@@ -102,6 +114,7 @@ fn time_workload<T: Elem, H: Heap<T>, W: Workload>(n: u64) -> f64 {
             )
                 .finish()
                 .expect("Could not initialize perfcnt. Run:\necho '1' | sudo tee /proc/sys/kernel/perf_event_paranoid\n");
+            let mut dtlb_misses = dtlb_miss_counter();
             // let l3_cache_misses = PerfCounterBuilderLinux::from_cache_event(
             //     CacheId::LL,
             //     CacheOpId::Read,
@@ -118,6 +131,7 @@ fn time_workload<T: Elem, H: Heap<T>, W: Workload>(n: u64) -> f64 {
             // l1_cache_misses.start().unwrap();
             hw_cache_misses.start().unwrap();
             hw_cache_references.start().unwrap();
+            dtlb_misses.start().unwrap();
             // let _ = l3_cache_misses.as_ref().map(|c| c.start().unwrap());
 
             let start = std::time::Instant::now();
@@ -128,6 +142,7 @@ fn time_workload<T: Elem, H: Heap<T>, W: Workload>(n: u64) -> f64 {
             // l1_cache_misses.stop().unwrap();
             hw_cache_misses.stop().unwrap();
             hw_cache_references.stop().unwrap();
+            dtlb_misses.stop().unwrap();
             // let _ = l3_cache_misses.as_ref().map(|c| c.stop().unwrap());
 
             result = Result {
@@ -150,6 +165,7 @@ fn time_workload<T: Elem, H: Heap<T>, W: Workload>(n: u64) -> f64 {
                 //     .map(|mut c| c.read().unwrap() as f64)
                 //     .unwrap_or_default(),
                 l3_cache_misses: 0.0,
+                dtlb_misses: dtlb_misses.read().unwrap() as f64,
             };
         }
 
@@ -288,6 +304,10 @@ where
     #[cfg(feature = "avx512")]
     bench::<T, ConfigurableSimdQuickHeap<T, Avx512<true>, MedianOfM<3>>>();
 
+    // With radix-aligned layers, to compare against the radix heap.
+    #[cfg(feature = "avx2")]
+    bench::<T, ConfigurableSimdQuickHeap<T, Avx2, RadixPivot>>();
+
     // ENGINEERED
     #[cfg(feature = "ffi")]
     match TypeId::of::<T>() {
@@ -326,6 +346,35 @@ fn bench_table() {
     #[cfg(feature = "avx512")]
     bench::<T, ConfigurableSimdQuickHeap<T, Avx512<true>, MedianOfM<3>>>();
 
+    // With huge pages, to compare the dTLB misses.
+    #[cfg(feature = "avx2")]
+    bench::<
+        T,
+        ConfigurableSimdQuickHeap<
+            T,
+            Avx2,
+            MedianOfM<3>,
+            NoRebalancing,
+            32,
+            true,
+            BinarySplit,
+            HugePages,
+        >,
+    >();
+
+    // Without prefetching, to compare the cache misses.
+    #[cfg(feature = "avx2")]
+    bench::<
+        T,
+        simd_quickheap::StreamThreshold<
+            ConfigurableSimdQuickHeap<T, Avx2, MedianOfM<3>>,
+            { usize::MAX },
+        >,
+    >();
+
+    #[cfg(feature = "avx2")]
+    bench::<T, CompactSimdQuickHeap<T, Avx2>>();
+
     // QUICKHEAP
     bench::<T, scalar_quickheap::ScalarQuickHeap<T, 3, false, { Search::LinearScan }>>();
 
@@ -368,6 +417,8 @@ fn bench_table() {
 
     // MONOTONE
     bench::<T, impls::RadixHeap<T>>();
+    #[cfg(feature = "avx2")]
+    bench::<T, ConfigurableSimdQuickHeap<T, Avx2, RadixPivot>>();
 }
 
 fn bench_comparisons() {
@@ -421,6 +472,8 @@ where
         bench::<T, ConfigurableSimdQuickHeap<T, Avx2, RandomPivot>>();
         bench::<T, ConfigurableSimdQuickHeap<T, Avx2, MedianOfM<3>>>();
         bench::<T, ConfigurableSimdQuickHeap<T, Avx2, MedianOfM<5>>>();
+        // Unsorted bottom layer.
+        bench::<T, ConfigurableSimdQuickHeap<T, Avx2, MedianOfM<3>, NoRebalancing, 32, false>>();
     }
 
     #[cfg(feature = "avx512")]
@@ -428,11 +481,26 @@ where
         bench::<T, ConfigurableSimdQuickHeap<T, Avx512<true>, RandomPivot>>();
         bench::<T, ConfigurableSimdQuickHeap<T, Avx512<true>, MedianOfM<3>>>();
         bench::<T, ConfigurableSimdQuickHeap<T, Avx512<true>, MedianOfM<5>>>();
+        // Unsorted bottom layer.
+        bench::<
+            T,
+            ConfigurableSimdQuickHeap<T, Avx512<true>, MedianOfM<3>, NoRebalancing, 32, false>,
+        >();
     }
 }
 
 fn bench_memory() {
     type T = i64;
+    type HugePageHeap = ConfigurableSimdQuickHeap<
+        T,
+        quickheap::Simd,
+        MedianOfM<3>,
+        NoRebalancing,
+        32,
+        true,
+        BinarySplit,
+        HugePages,
+    >;
 
     fn bench<H: Heap<T>, W: Workload>() {
         eprintln!("Workload: {}", type_name::<W>());
@@ -440,13 +508,24 @@ fn bench_memory() {
             let n = 1 << exp;
 
             let f = W::setup::<T, H>(n);
+            #[cfg(feature = "perf")]
+            let mut dtlb_misses = dtlb_miss_counter();
+            #[cfg(feature = "perf")]
+            dtlb_misses.start().unwrap();
             let h = f();
             let cap = h.capacity();
-            eprintln!(
+            eprint!(
                 "{:>20}  n: {n:>10}  Cap: {cap:>10}  Ratio: {:>6.3}",
                 type_name::<H>(),
                 cap as f64 / n as f64
             );
+            #[cfg(feature = "perf")]
+            {
+                dtlb_misses.stop().unwrap();
+                let misses = dtlb_misses.read().unwrap() as f64;
+                eprint!("  dTLB misses/n: {:>8.3}", misses / n as f64);
+            }
+            eprintln!();
         }
         eprintln!();
     }
@@ -454,6 +533,14 @@ fn bench_memory() {
     bench::<SimdQuickHeap<T>, MonotoneConstantSize>();
     bench::<SimdQuickHeap<T>, MonotoneWiggle>();
     bench::<SimdQuickHeap<T>, RandomWiggle>();
+    bench::<HugePageHeap, HeapSort>();
+    bench::<HugePageHeap, MonotoneConstantSize>();
+    bench::<HugePageHeap, MonotoneWiggle>();
+    bench::<HugePageHeap, RandomWiggle>();
+    bench::<CompactSimdQuickHeap<T>, HeapSort>();
+    bench::<CompactSimdQuickHeap<T>, MonotoneConstantSize>();
+    bench::<CompactSimdQuickHeap<T>, MonotoneWiggle>();
+    bench::<CompactSimdQuickHeap<T>, RandomWiggle>();
 }
 
 fn main() {
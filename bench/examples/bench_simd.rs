@@ -7,6 +7,8 @@ use clap::Parser;
 use quickheap::{Avx2, SimdElem, pivot_strategies::MedianOfM};
 
 use quickheap::rebalancing_strategies::{NaiveLogRebalancing, NoRebalancing, PivotForgetting};
+#[cfg(feature = "avx2")]
+use quickheap::splitter_strategies::SampleSplit;
 
 use bench::workloads::*;
 use bench::*;
@@ -168,6 +170,30 @@ where
                 true,
             >,
         >(minpow, maxpow);
+
+        // Larger sorted bottom layers
+        bench::<
+            T,
+            quickheap::ConfigurableSimdQuickHeap<T, Avx2, MedianOfM<3>, NoRebalancing, 32, true>,
+        >(minpow, maxpow);
+        bench::<
+            T,
+            quickheap::ConfigurableSimdQuickHeap<T, Avx2, MedianOfM<3>, NoRebalancing, 64, true>,
+        >(minpow, maxpow);
+
+        // Multi-way partitioning of large layers
+        bench::<
+            T,
+            quickheap::ConfigurableSimdQuickHeap<
+                T,
+                Avx2,
+                MedianOfM<3>,
+                NoRebalancing,
+                32,
+                true,
+                SampleSplit<16>,
+            >,
+        >(minpow, maxpow);
     }
 
     #[cfg(feature = "avx512")]
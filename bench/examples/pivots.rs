@@ -4,7 +4,9 @@ use quickheap::Avx2;
 use quickheap::Avx512;
 
 use quickheap::ConfigurableSimdQuickHeap as SimdQuickHeap;
-use quickheap::pivot_strategies::{CbrtPivot, Log2Pivot, MedianOfM, TablePivot};
+use quickheap::pivot_strategies::{
+    AdaptivePivot, CbrtPivot, Log2Pivot, MedianOfM, RangeMidpointPivot, SketchPivot, TablePivot,
+};
 use quickheap::rebalancing_strategies::NoRebalancing;
 
 use bench::workloads::*;
@@ -123,6 +125,37 @@ fn main() {
         time_workload::<T, SimdQuickHeap<T, Avx2, TablePivot, NoRebalancing, 16>, RandomConstantSize>(
             n,
         );
+        time_workload::<
+            T,
+            SimdQuickHeap<T, Avx2, AdaptivePivot, NoRebalancing, 16>,
+            RandomConstantSize,
+        >(n);
+        time_workload::<
+            T,
+            SimdQuickHeap<T, Avx2, RangeMidpointPivot, NoRebalancing, 16>,
+            RandomConstantSize,
+        >(n);
+        time_workload::<
+            T,
+            SimdQuickHeap<T, Avx2, SketchPivot, NoRebalancing, 16>,
+            RandomConstantSize,
+        >(n);
+
+        // Steady-state workloads, where the sketch replaces sampling.
+        time_workload::<
+            T,
+            SimdQuickHeap<T, Avx2, MedianOfM<3>, NoRebalancing, 16>,
+            MonotoneConstantSize,
+        >(n);
+        time_workload::<
+            T,
+            SimdQuickHeap<T, Avx2, SketchPivot, NoRebalancing, 16>,
+            MonotoneConstantSize,
+        >(n);
+        time_workload::<T, SimdQuickHeap<T, Avx2, MedianOfM<3>, NoRebalancing, 16>, RandomWiggle>(
+            n,
+        );
+        time_workload::<T, SimdQuickHeap<T, Avx2, SketchPivot, NoRebalancing, 16>, RandomWiggle>(n);
     }
     #[cfg(feature = "avx512")]
     {
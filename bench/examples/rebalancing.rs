@@ -5,7 +5,9 @@ use quickheap::Avx512;
 
 use quickheap::ConfigurableSimdQuickHeap as SimdQuickHeap;
 use quickheap::pivot_strategies::MedianOfM;
-use quickheap::rebalancing_strategies::{NaiveLogRebalancing, NoRebalancing, PivotForgetting};
+use quickheap::rebalancing_strategies::{
+    NaiveLogRebalancing, NoRebalancing, PivotForgetting, PushSplitting,
+};
 
 use bench::workloads::*;
 use bench::*;
@@ -87,6 +89,9 @@ fn main() {
             SimdQuickHeap<T, Avx2, MedianOfM<3>, PivotForgetting<2, 512>, 16>,
             RandomWiggle,
         >(n);
+        time_workload::<T, SimdQuickHeap<T, Avx2, MedianOfM<3>, PushSplitting, 16>, RandomWiggle>(
+            n,
+        );
 
         // time_workload::<
         //     T,
@@ -6,6 +6,8 @@ pub struct DijkstraQuery<'g, HeapT: Heap<u64>> {
     heap: HeapT,
     graph: &'g Graph<u32>,
     distances: Vec<u32>,
+    /// The relaxed edges of the current vertex, pushed as one batch.
+    batch: Vec<u64>,
 }
 
 impl<'g, HeapT: Heap<u64>> DijkstraQuery<'g, HeapT> {
@@ -13,6 +15,7 @@ impl<'g, HeapT: Heap<u64>> DijkstraQuery<'g, HeapT> {
         Self {
             heap: HeapT::default(),
             distances: vec![u32::MAX; graph.num_vertices()],
+            batch: vec![],
             graph,
         }
     }
@@ -32,14 +35,16 @@ impl<'g, HeapT: Heap<u64>> DijkstraQuery<'g, HeapT> {
             let (v, dist_to_v) = unpack_id_key_tuple_from_u64(next_elem);
 
             if dist_to_v == self.distances[v] {
+                self.batch.clear();
                 for (_id, Edge { to, weight, .. }) in self.graph.outgoing_edges(v) {
                     let to_dist = dist_to_v + weight;
                     if to_dist < self.distances[to] {
                         self.distances[to] = to_dist;
-                        push_count += 1;
-                        self.heap.push(pack_id_key_tuple_to_u64(to, to_dist));
+                        self.batch.push(pack_id_key_tuple_to_u64(to, to_dist));
                     }
                 }
+                push_count += self.batch.len();
+                self.heap.push_batch(&self.batch);
             }
         }
         (push_count, pop_count)
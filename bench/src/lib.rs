@@ -40,6 +40,12 @@ where
     type CountedHeap: CountingHeapT<T>;
     fn default() -> Self;
     fn push(&mut self, t: T);
+    /// Push all of `ts`. Heaps without a batched push push them one by one.
+    fn push_batch(&mut self, ts: &[T]) {
+        for &t in ts {
+            self.push(t);
+        }
+    }
     fn pop(&mut self) -> Option<T>;
     fn capacity(&self) -> usize {
         0
@@ -60,7 +66,7 @@ mod test {
     use crate::workloads::{Elem, Workload};
 
     #[cfg(feature = "avx2")]
-    use quickheap::{Avx2, ConfigurableSimdQuickHeap, rebalancing_strategies};
+    use quickheap::{Avx2, CompactSimdQuickHeap, ConfigurableSimdQuickHeap, rebalancing_strategies};
 
     use std::marker::PhantomData;
 
@@ -145,6 +151,8 @@ mod test {
                 true,
             >,
         >::run(n);
+        #[cfg(feature = "avx2")]
+        TestHeap::<T, Base, CompactSimdQuickHeap<T, Avx2>>::run(n);
         #[cfg(feature = "avx512")]
         TestHeap::<T, Base, ConfigurableSimdQuickHeap<T, Avx512<false>, 8, 3, true>>::run(n);
         #[cfg(feature = "avx512")]
@@ -4,10 +4,43 @@ impl<
     T: quickheap::Elem + workloads::Elem,
     S: quickheap::SimdElem<T>,
     P: quickheap::pivot_strategies::PivotStrategy,
-    R: quickheap::rebalancing_strategies::RebalancingStrategy<T>,
+    R: quickheap::rebalancing_strategies::RebalancingStrategy<T> + Default,
     const N: usize,
     const SORT: bool,
-> Heap<T> for quickheap::ConfigurableSimdQuickHeap<T, S, P, R, N, SORT>
+    Sp: quickheap::splitter_strategies::SplitterStrategy,
+    A: quickheap::bucket_alloc::BucketAlloc + Default,
+> Heap<T> for quickheap::ConfigurableSimdQuickHeap<T, S, P, R, N, SORT, Sp, A>
+{
+    type CountedType = workloads::CountComparisons<T>;
+    type CountedHeap = NoHeap;
+
+    fn default() -> Self {
+        Default::default()
+    }
+
+    fn push(&mut self, t: T) {
+        self.push(t)
+    }
+
+    fn push_batch(&mut self, ts: &[T]) {
+        self.push_batch(ts)
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        self.pop()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<
+    T: quickheap::Elem + workloads::Elem,
+    S: quickheap::SimdElem<T>,
+    P: quickheap::pivot_strategies::PivotStrategy,
+    const N: usize,
+> Heap<T> for quickheap::CompactSimdQuickHeap<T, S, P, N>
 {
     type CountedType = workloads::CountComparisons<T>;
     type CountedHeap = NoHeap;
@@ -28,3 +61,47 @@ impl<
         self.capacity()
     }
 }
+
+/// A [`quickheap::ConfigurableSimdQuickHeap`] with stream threshold `BYTES`,
+/// to compare cache misses with and without prefetching.
+pub struct StreamThreshold<H, const BYTES: usize>(H);
+
+impl<
+    T: quickheap::Elem + workloads::Elem,
+    S: quickheap::SimdElem<T>,
+    P: quickheap::pivot_strategies::PivotStrategy,
+    R: quickheap::rebalancing_strategies::RebalancingStrategy<T> + Default,
+    const N: usize,
+    const SORT: bool,
+    Sp: quickheap::splitter_strategies::SplitterStrategy,
+    A: quickheap::bucket_alloc::BucketAlloc + Default,
+    const BYTES: usize,
+> Heap<T>
+    for StreamThreshold<quickheap::ConfigurableSimdQuickHeap<T, S, P, R, N, SORT, Sp, A>, BYTES>
+{
+    type CountedType = workloads::CountComparisons<T>;
+    type CountedHeap = NoHeap;
+
+    fn default() -> Self {
+        let mut h: quickheap::ConfigurableSimdQuickHeap<T, S, P, R, N, SORT, Sp, A> =
+            Default::default();
+        h.set_stream_threshold(BYTES);
+        Self(h)
+    }
+
+    fn push(&mut self, t: T) {
+        self.0.push(t)
+    }
+
+    fn push_batch(&mut self, ts: &[T]) {
+        self.0.push_batch(ts)
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
@@ -0,0 +1,404 @@
+//! Storage for the layers of a [`ConfigurableSimdQuickHeap`](crate::ConfigurableSimdQuickHeap),
+//! with a pluggable allocation backend.
+
+use ::alloc::{
+    alloc::{self, Layout},
+    sync::Arc,
+};
+use core::{
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+/// An allocation backend for [`Bucket`]s.
+///
+/// `dealloc` and `realloc` get the same `layout` that the memory was allocated with,
+/// so a backend may adjust the layout, as long as it does so deterministically.
+///
+/// Every bucket holds its own clone of the backend, so stateful backends such as
+/// arenas should be cheap handles to shared state.
+pub trait BucketAlloc: Clone {
+    /// Allocate memory for `layout`, or return null.
+    ///
+    /// # Safety
+    /// `layout` must have non-zero size.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8;
+
+    /// Free memory from [`alloc`](BucketAlloc::alloc).
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by `self` with `layout`.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+
+    /// Move an allocation to `new_size` bytes, or return null and leave it unchanged.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by `self` with `layout`, and `new_size` must be non-zero.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe {
+            let new = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+            if !new.is_null() {
+                core::ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            new
+        }
+    }
+}
+
+/// The global allocator, as used by `Vec`.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Global;
+impl BucketAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { alloc::alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { alloc::dealloc(ptr, layout) }
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { alloc::realloc(ptr, layout, new_size) }
+    }
+}
+
+/// A [`BucketAlloc`] returned null, or the requested capacity overflowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocError;
+
+impl core::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("memory allocation failed")
+    }
+}
+
+impl core::error::Error for AllocError {}
+
+/// The width in bytes of the widest SIMD registers.
+pub const SIMD_ALIGN: usize = 64;
+
+/// The size of a transparent huge page.
+const HUGE_PAGE: usize = 2 << 20;
+
+/// Aligns buckets to [`SIMD_ALIGN`] bytes, and buckets of at least `HUGE` bytes to huge pages.
+///
+/// On Linux, the huge buckets are advised to be backed by transparent huge pages with
+/// `madvise(MADV_HUGEPAGE)`, which reduces TLB misses when scanning the large top layers.
+/// `HUGE = usize::MAX` only aligns. Default 4 MiB.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct HugePages<const HUGE: usize = { 4 << 20 }>;
+impl<const HUGE: usize> HugePages<HUGE> {
+    /// The layout that is actually allocated for `layout`.
+    fn adjust(layout: Layout) -> Layout {
+        let (size, align) = if layout.size() >= HUGE {
+            // Whole huge pages, so that `madvise` covers all of the bucket.
+            (layout.size().next_multiple_of(HUGE_PAGE), HUGE_PAGE)
+        } else {
+            (layout.size(), SIMD_ALIGN)
+        };
+        Layout::from_size_align(size, align.max(layout.align())).unwrap()
+    }
+}
+impl<const HUGE: usize> BucketAlloc for HugePages<HUGE> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let layout = Self::adjust(layout);
+        let ptr = unsafe { alloc::alloc(layout) };
+        #[cfg(target_os = "linux")]
+        if !ptr.is_null() && layout.align() == HUGE_PAGE {
+            // Only a hint, so failure is fine.
+            unsafe { libc::madvise(ptr as *mut libc::c_void, layout.size(), libc::MADV_HUGEPAGE) };
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { alloc::dealloc(ptr, Self::adjust(layout)) }
+    }
+}
+
+/// An allocation that is shared by the buckets from [`Bucket::split_off`].
+struct Region {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// Only the buckets in a region access it, and only to free it.
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+/// A growable array of `Copy` elements, like `Vec<T>`, allocated by `A`.
+///
+/// The heap reads a few SIMD lanes past the length, but always within the allocation.
+pub struct Bucket<T: Copy, A: BucketAlloc = Global> {
+    ptr: NonNull<T>,
+    len: usize,
+    cap: usize,
+    alloc: A,
+    /// The allocation that `ptr..ptr + cap` lies in, when it is shared with other buckets.
+    region: Option<Arc<Region>>,
+}
+
+unsafe impl<T: Copy + Send, A: BucketAlloc + Send> Send for Bucket<T, A> {}
+unsafe impl<T: Copy + Sync, A: BucketAlloc + Sync> Sync for Bucket<T, A> {}
+
+impl<T: Copy, A: BucketAlloc + Default> Bucket<T, A> {
+    /// Return an empty bucket, without allocating.
+    pub fn new() -> Self {
+        Self::new_in(A::default())
+    }
+}
+
+impl<T: Copy, A: BucketAlloc> Bucket<T, A> {
+    /// Return an empty bucket that allocates through `alloc`, without allocating.
+    pub fn new_in(alloc: A) -> Self {
+        assert!(size_of::<T>() > 0);
+        Self {
+            ptr: NonNull::dangling(),
+            len: 0,
+            cap: 0,
+            alloc,
+            region: None,
+        }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    /// # Safety
+    /// `len` must be at most the capacity, and the first `len` elements must be initialized.
+    pub unsafe fn set_len(&mut self, len: usize) {
+        debug_assert!(len <= self.cap);
+        self.len = len;
+    }
+
+    /// Make sure there is capacity for at least `additional` more elements.
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self.len.checked_add(additional).expect("capacity overflow");
+        if needed > self.cap {
+            let cap = needed.max(2 * self.cap).max(8);
+            if let Err(layout) = self.grow(cap) {
+                alloc::handle_alloc_error(layout)
+            }
+        }
+    }
+
+    /// Like [`reserve`](Bucket::reserve), but return an error instead of aborting.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        let needed = self.len.checked_add(additional).ok_or(AllocError)?;
+        if needed > self.cap {
+            let cap = needed.max(2 * self.cap).max(8);
+            self.grow(cap).map_err(|_| AllocError)?;
+        }
+        Ok(())
+    }
+
+    /// Move the elements from `at` on into a new bucket, without copying them.
+    ///
+    /// Both buckets keep using the current allocation, with all spare capacity going to the
+    /// new one, until they outgrow their part of it.
+    pub fn split_off(&mut self, at: usize) -> Self {
+        assert!(at <= self.len);
+        let alloc = self.alloc.clone();
+        if self.cap == 0 {
+            return Self::new_in(alloc);
+        }
+        let region = self.region.get_or_insert_with(|| {
+            Arc::new(Region {
+                ptr: self.ptr.cast(),
+                layout: Layout::array::<T>(self.cap).unwrap(),
+            })
+        });
+        let tail = Self {
+            ptr: unsafe { self.ptr.add(at) },
+            len: self.len - at,
+            cap: self.cap - at,
+            alloc,
+            region: Some(region.clone()),
+        };
+        self.len = at;
+        self.cap = at;
+        tail
+    }
+
+    /// Free the shared allocation if this was the last bucket in it.
+    fn release(&self, region: Arc<Region>) {
+        if let Some(region) = Arc::into_inner(region) {
+            unsafe { self.alloc.dealloc(region.ptr.as_ptr(), region.layout) };
+        }
+    }
+
+    /// Grow to exactly `cap` elements, or return the failed layout.
+    fn grow(&mut self, cap: usize) -> Result<(), Layout> {
+        let layout = Layout::array::<T>(cap).map_err(|_| Layout::new::<T>())?;
+        if let Some(region) = self.region.take() {
+            match Arc::try_unwrap(region) {
+                // The other buckets are gone, so take over the whole allocation.
+                Ok(region) => unsafe {
+                    let start = region.ptr.cast::<T>();
+                    core::ptr::copy(self.ptr.as_ptr(), start.as_ptr(), self.len);
+                    self.ptr = start;
+                    self.cap = region.layout.size() / size_of::<T>();
+                    if self.cap >= cap {
+                        return Ok(());
+                    }
+                },
+                // Move out into a new allocation.
+                Err(region) => unsafe {
+                    let Some(ptr) = NonNull::new(self.alloc.alloc(layout) as *mut T) else {
+                        self.region = Some(region);
+                        return Err(layout);
+                    };
+                    core::ptr::copy_nonoverlapping(self.ptr.as_ptr(), ptr.as_ptr(), self.len);
+                    self.ptr = ptr;
+                    self.cap = cap;
+                    self.release(region);
+                    return Ok(());
+                },
+            }
+        }
+        let ptr = unsafe {
+            if self.cap == 0 {
+                self.alloc.alloc(layout)
+            } else {
+                let old = Layout::array::<T>(self.cap).unwrap();
+                self.alloc
+                    .realloc(self.ptr.as_ptr() as *mut u8, old, layout.size())
+            }
+        };
+        let ptr = NonNull::new(ptr as *mut T).ok_or(layout)?;
+        self.ptr = ptr;
+        self.cap = cap;
+        Ok(())
+    }
+
+    pub fn push(&mut self, t: T) {
+        if self.len == self.cap {
+            self.reserve(1);
+        }
+        unsafe { self.ptr.add(self.len).write(t) };
+        self.len += 1;
+    }
+
+    /// Like [`push`](Bucket::push), but return an error instead of aborting.
+    pub fn try_push(&mut self, t: T) -> Result<(), AllocError> {
+        if self.len == self.cap {
+            self.try_reserve(1)?;
+        }
+        unsafe { self.ptr.add(self.len).write(t) };
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { self.ptr.add(self.len).read() })
+    }
+
+    /// Remove element `i` and replace it by the last one.
+    pub fn swap_remove(&mut self, i: usize) -> T {
+        let t = self[i];
+        self[i] = self[self.len - 1];
+        self.len -= 1;
+        t
+    }
+
+    /// Remove element `i` and shift the following ones down.
+    pub fn remove(&mut self, i: usize) -> T {
+        let t = self[i];
+        self.copy_within(i + 1.., i);
+        self.len -= 1;
+        t
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Remove all elements. A bucket from [`split_off`](Bucket::split_off) also gives up its
+    /// part of the shared allocation.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        if let Some(region) = self.region.take() {
+            self.release(region);
+            self.ptr = NonNull::dangling();
+            self.cap = 0;
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, ts: &[T]) {
+        self.reserve(ts.len());
+        unsafe {
+            core::ptr::copy_nonoverlapping(ts.as_ptr(), self.ptr.add(self.len).as_ptr(), ts.len());
+        }
+        self.len += ts.len();
+    }
+
+    /// Truncate to `len` elements, or append copies of `t` until there are `len`.
+    pub fn resize(&mut self, len: usize, t: T) {
+        if len > self.len {
+            self.reserve(len - self.len);
+            for i in self.len..len {
+                unsafe { self.ptr.add(i).write(t) };
+            }
+        }
+        self.len = len;
+    }
+}
+
+impl<T: Copy, A: BucketAlloc + Default> Default for Bucket<T, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy, A: BucketAlloc> Drop for Bucket<T, A> {
+    fn drop(&mut self) {
+        if let Some(region) = self.region.take() {
+            self.release(region);
+        } else if self.cap > 0 {
+            let layout = Layout::array::<T>(self.cap).unwrap();
+            unsafe { self.alloc.dealloc(self.ptr.as_ptr() as *mut u8, layout) };
+        }
+    }
+}
+
+impl<T: Copy, A: BucketAlloc> Deref for Bucket<T, A> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy, A: BucketAlloc> DerefMut for Bucket<T, A> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy + core::fmt::Debug, A: BucketAlloc> core::fmt::Debug for Bucket<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,246 @@
+//! Choose the configuration of a heap at runtime instead of through type parameters.
+
+use crate::{
+    Avx2, Avx512, ConfigurableSimdQuickHeap, DEFAULT_N, DYNAMIC_N, Elem, SimdElem,
+    bucket_alloc::AllocError, pivot_strategies::DynPivot, rebalancing_strategies::DynRebalancing,
+};
+
+/// The SIMD backend of a [`DynQuickHeap`]. Default AVX-512 if available at compile time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[cfg_attr(not(target_feature = "avx512f"), default)]
+    Avx2,
+    /// Falls back to AVX2 unless the crate is compiled with AVX-512 enabled.
+    #[cfg_attr(target_feature = "avx512f", default)]
+    Avx512,
+}
+
+/// Whether the `Avx512` backend is compiled with AVX-512 instructions. Without
+/// `-C target-feature=+avx512f`, its intrinsics become out-of-line calls and it is slower
+/// than AVX2.
+const AVX512: bool = cfg!(target_feature = "avx512f");
+
+/// Configures a [`DynQuickHeap`] at runtime, for example from a config file.
+///
+/// Every setting defaults to that of [`SimdQuickHeap`](crate::SimdQuickHeap).
+///
+/// ```
+/// use quickheap::{Backend, QuickHeapBuilder};
+/// let mut q = QuickHeapBuilder::new()
+///     .backend(Backend::Avx2)
+///     .bottom_threshold(64)
+///     .seed(42)
+///     .build::<u32>();
+/// q.push(3);
+/// q.push(1);
+/// assert_eq!(q.pop(), Some(1));
+/// ```
+#[derive(Clone, Default)]
+pub struct QuickHeapBuilder {
+    backend: Backend,
+    pivot: DynPivot,
+    rebalancing: DynRebalancing,
+    bottom_threshold: Option<usize>,
+    unsorted: bool,
+    capacity: usize,
+    seed: Option<u64>,
+}
+
+impl QuickHeapBuilder {
+    /// Return a builder with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the SIMD backend `backend`.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Pick pivots with `pivot`. Its learned state, if any, is copied into each built heap.
+    pub fn pivot_strategy(mut self, pivot: DynPivot) -> Self {
+        self.pivot = pivot;
+        self
+    }
+
+    /// Rebalance the layers with `rebalancing`. Its state, if any, is copied into each built heap.
+    pub fn rebalancing(mut self, rebalancing: DynRebalancing) -> Self {
+        self.rebalancing = rebalancing;
+        self
+    }
+
+    /// Partition until the bottom layer has at most `n` elements. Default `32`.
+    ///
+    /// Panics when `n` is 0.
+    pub fn bottom_threshold(mut self, n: usize) -> Self {
+        assert!(n > 0, "the bottom layer cannot be split to 0 elements");
+        self.bottom_threshold = Some(n);
+        self
+    }
+
+    /// Whether to keep the bottom layer sorted. Default `true`.
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.unsorted = !sorted;
+        self
+    }
+
+    /// Reserve room for `capacity` elements before the first layer is split.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sample pivots with a PRNG seeded by `seed`, so that runs can be replayed exactly.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Return an empty heap with this configuration.
+    ///
+    /// Panics when the initial capacity cannot be allocated.
+    pub fn build<T: Elem>(&self) -> DynQuickHeap<T>
+    where
+        Avx2: SimdElem<T>,
+        Avx512: SimdElem<T>,
+    {
+        self.try_build()
+            .expect("failed to allocate the initial capacity")
+    }
+
+    /// Return an empty heap with this configuration, or an error when the initial capacity
+    /// cannot be allocated.
+    pub fn try_build<T: Elem>(&self) -> Result<DynQuickHeap<T>, AllocError>
+    where
+        Avx2: SimdElem<T>,
+        Avx512: SimdElem<T>,
+    {
+        use Backend as B;
+        use Inner as I;
+        let sorted = !self.unsorted;
+        let backend = match self.backend {
+            B::Avx512 if !AVX512 => B::Avx2,
+            backend => backend,
+        };
+        let inner = match (backend, sorted) {
+            (B::Avx2, true) => I::Avx2(self.heap()?),
+            (B::Avx2, false) => I::Avx2Unsorted(self.heap()?),
+            (B::Avx512, true) => I::Avx512(self.heap()?),
+            (B::Avx512, false) => I::Avx512Unsorted(self.heap()?),
+        };
+        Ok(DynQuickHeap(inner))
+    }
+
+    fn heap<T: Elem, S: SimdElem<T>, const SORT: bool>(
+        &self,
+    ) -> Result<Heap<T, S, SORT>, AllocError> {
+        let mut q = match self.seed {
+            Some(seed) => Heap::with_seed(seed),
+            None => Heap::default(),
+        };
+        q.pivot_strategy = self.pivot.clone();
+        q.rebalancing = self.rebalancing.clone();
+        q.set_bottom_threshold(self.bottom_threshold.unwrap_or(DEFAULT_N));
+        if self.capacity > 0 {
+            q.try_reserve(self.capacity)?;
+        }
+        Ok(q)
+    }
+}
+
+type Heap<T, S, const SORT: bool> =
+    ConfigurableSimdQuickHeap<T, S, DynPivot, DynRebalancing, DYNAMIC_N, SORT>;
+
+/// A heap whose configuration is chosen at runtime by a [`QuickHeapBuilder`].
+///
+/// Each call dispatches on the configuration once, and then runs the same code as the
+/// corresponding [`ConfigurableSimdQuickHeap`].
+pub struct DynQuickHeap<T: Elem>(Inner<T>)
+where
+    Avx2: SimdElem<T>,
+    Avx512: SimdElem<T>;
+
+enum Inner<T: Elem>
+where
+    Avx2: SimdElem<T>,
+    Avx512: SimdElem<T>,
+{
+    Avx2(Heap<T, Avx2, true>),
+    Avx2Unsorted(Heap<T, Avx2, false>),
+    Avx512(Heap<T, Avx512, true>),
+    Avx512Unsorted(Heap<T, Avx512, false>),
+}
+
+/// Evaluate `$e` with `$q` bound to the heap inside `$inner`.
+macro_rules! dispatch {
+    ($inner:expr, $q:ident => $e:expr) => {
+        match $inner {
+            Inner::Avx2($q) => $e,
+            Inner::Avx2Unsorted($q) => $e,
+            Inner::Avx512($q) => $e,
+            Inner::Avx512Unsorted($q) => $e,
+        }
+    };
+}
+
+impl<T: Elem> DynQuickHeap<T>
+where
+    Avx2: SimdElem<T>,
+    Avx512: SimdElem<T>,
+{
+    /// Return the SIMD backend in use, which is AVX2 when AVX-512 was asked for but is not
+    /// enabled at compile time.
+    pub fn backend(&self) -> Backend {
+        match self.0 {
+            Inner::Avx2(_) | Inner::Avx2Unsorted(_) => Backend::Avx2,
+            Inner::Avx512(_) | Inner::Avx512Unsorted(_) => Backend::Avx512,
+        }
+    }
+
+    /// Return the total capacity over all layers.
+    pub fn capacity(&self) -> usize {
+        dispatch!(&self.0, q => q.capacity())
+    }
+
+    /// Return the number of elements currently in the heap.
+    pub fn len(&self) -> usize {
+        dispatch!(&self.0, q => q.len())
+    }
+
+    /// Return whether the heap contains no elements.
+    pub fn is_empty(&self) -> bool {
+        dispatch!(&self.0, q => q.is_empty())
+    }
+
+    /// Push `t` onto the heap.
+    pub fn push(&mut self, t: T) {
+        dispatch!(&mut self.0, q => q.push(t))
+    }
+
+    /// Push `t` onto the heap, or return an error and leave the heap unchanged
+    /// when its layer cannot grow.
+    pub fn try_push(&mut self, t: T) -> Result<(), AllocError> {
+        dispatch!(&mut self.0, q => q.try_push(t))
+    }
+
+    /// Push all elements of `ts` onto the heap.
+    pub fn push_batch(&mut self, ts: &[T]) {
+        dispatch!(&mut self.0, q => q.push_batch(ts))
+    }
+
+    /// Pop the smallest element from the queue.
+    pub fn pop(&mut self) -> Option<T> {
+        dispatch!(&mut self.0, q => q.pop())
+    }
+
+    /// Remove all elements, and forget the layers.
+    pub fn clear(&mut self) {
+        dispatch!(&mut self.0, q => q.clear())
+    }
+
+    /// Remove all elements, but keep the current pivots as hints for the next pushes.
+    pub fn clear_keep_pivots(&mut self) {
+        dispatch!(&mut self.0, q => q.clear_keep_pivots())
+    }
+}
@@ -12,6 +12,8 @@
 
 #![allow(non_snake_case)]
 
+use alloc::boxed::Box;
+
 use crate::SimdQuickHeap;
 
 /// Defines the opaque type and `extern "C"` functions for one element type.
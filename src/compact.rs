@@ -0,0 +1,204 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::marker::PhantomData;
+
+use crate::{DEFAULT_N, Elem, Simd, bucket_alloc::Bucket, pivot_strategies, rng::Rng, simd};
+
+/// A SimdQuickHeap variant that stores all layers in a single circular array.
+///
+/// This is the layout of the original in-place quickheap by Navarro and Paredes:
+/// layer 0 (the largest elements) is at the front of the array and the active layer
+/// (the smallest elements) at the back.
+/// Layers are split in place with a two-ended SIMD partition, so that no scratch
+/// layer is needed and the capacity stays close to the number of elements.
+///
+/// Pushes that go to a lower layer move one element per layer they pass,
+/// which makes them slower than for [`ConfigurableSimdQuickHeap`](crate::ConfigurableSimdQuickHeap).
+///
+/// Unlike [`ConfigurableSimdQuickHeap`](crate::ConfigurableSimdQuickHeap), it does not escalate
+/// to better pivots after bad splits, and does not mark layers of equal elements.
+/// A layer with many copies of one element is instead split at the position of the pivot,
+/// as if the copies were distinct, so popping them takes as many partitions as popping
+/// distinct elements.
+///
+/// - `T`: the element type.
+/// - `S`: the SIMD tag: [`Avx2`](crate::Avx2) or [`Avx512`](crate::Avx512). Default AVX-512 if available.
+/// - `P`: the pivoting strategy; see [`pivot_strategies`]. Default median of 3.
+/// - `N`: partition until the bottom layer is <N. Default `32`. The bottom layer is kept sorted.
+pub struct CompactSimdQuickHeap<
+    T: Elem,
+    S: simd::SimdElem<T> = Simd,
+    P: pivot_strategies::PivotStrategy = pivot_strategies::MedianOfM<3>,
+    const N: usize = DEFAULT_N,
+> {
+    /// All elements, ordered by layer.
+    /// The active layer is at the back, and is sorted decreasing when it has at most `N` elements.
+    data: VecDeque<T>,
+    /// A decreasing array of the pivots for all layers, as for `ConfigurableSimdQuickHeap`.
+    ///
+    /// This will have enough underlying capacity for out-of-bounds SIMD reads.
+    pivots: Bucket<T>,
+    /// A summary of `pivots` to speed up `push` with many layers.
+    tree: simd::PivotTree<T>,
+    /// The number of elements in each layer.
+    /// This is always 1 longer than `pivots`.
+    sizes: Vec<usize>,
+    /// Samples the pivots.
+    rng: Rng,
+
+    /// Picks the pivots, and learns from how well they split their layers.
+    pivot_strategy: P,
+    _backend: PhantomData<S>,
+}
+
+/// Return a default instance with room for plenty (128) layers.
+impl<T: Elem, S: simd::SimdElem<T>, P: pivot_strategies::PivotStrategy, const N: usize> Default
+    for CompactSimdQuickHeap<T, S, P, N>
+{
+    fn default() -> Self {
+        let mut sizes = Vec::with_capacity(128);
+        sizes.push(0);
+        let mut pivots = Bucket::new();
+        pivots.reserve(128);
+        Self {
+            data: VecDeque::new(),
+            pivots,
+            tree: Default::default(),
+            sizes,
+            rng: Rng::default(),
+            pivot_strategy: P::default(),
+            _backend: PhantomData,
+        }
+    }
+}
+
+impl<T: Elem, S: simd::SimdElem<T>, P: pivot_strategies::PivotStrategy, const N: usize>
+    CompactSimdQuickHeap<T, S, P, N>
+{
+    /// Return an empty heap whose pivots are sampled with a PRNG seeded by `seed`.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            ..Self::default()
+        }
+    }
+
+    /// Return the capacity of the underlying array.
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    /// Return the number of elements currently in the heap.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Return whether the heap contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Push `t` onto the heap.
+    pub fn push(&mut self, t: T) {
+        self.pivot_strategy.on_push::<T, S>(t);
+        let target_layer = simd::push_position::<T, S, _>(&self.pivots, &self.tree, t);
+
+        // Open a hole at the front, and move it to the start of the target layer
+        // by moving the last element of each layer in between to the start of that layer.
+        self.data.push_front(t);
+        let mut hole = 0;
+        for &size in &self.sizes[..target_layer] {
+            self.data[hole] = self.data[hole + size];
+            hole += size;
+        }
+
+        let size = &mut self.sizes[target_layer];
+        if target_layer == self.pivots.len() && *size < N {
+            // Move the hole past the larger elements in the sorted layer.
+            let end = hole + *size;
+            while hole < end && self.data[hole + 1] > t {
+                self.data[hole] = self.data[hole + 1];
+                hole += 1;
+            }
+        }
+        self.data[hole] = t;
+        *size += 1;
+    }
+
+    /// Pop the smallest element from the queue.
+    pub fn pop(&mut self) -> Option<T> {
+        // Only the top layer can be empty.
+        if self.data.is_empty() {
+            return None;
+        }
+        // Split the current layer as long as it is too large.
+        if self.sizes[self.pivots.len()] > N {
+            while self.sizes[self.pivots.len()] > N {
+                self.partition();
+            }
+            // Sort final layer decreasing.
+            S::sort_desc(self.active_layer());
+        }
+        // The minimum is at the back.
+        let min = self.data.pop_back().unwrap();
+        self.pivot_strategy.on_pop::<T, S>(min);
+
+        // Update the active layer.
+        let layer = self.pivots.len();
+        self.sizes[layer] -= 1;
+        if self.sizes[layer] == 0 && layer > 0 {
+            self.pivots.pop();
+            self.tree.sync(&self.pivots);
+            self.sizes.pop();
+
+            // Sort the new final layer decreasing if it's already small.
+            if self.sizes[layer - 1] <= N {
+                S::sort_desc(self.active_layer());
+            }
+        }
+
+        Some(min)
+    }
+
+    /// The elements of the active layer, as a contiguous slice.
+    fn active_layer(&mut self) -> &mut [T] {
+        let n = self.sizes[self.pivots.len()];
+        let back_len = self.data.as_slices().1.len();
+        if back_len != 0 && back_len < n {
+            self.data.make_contiguous();
+        }
+        let (front, back) = self.data.as_mut_slices();
+        let slice = if back.is_empty() { front } else { back };
+        let len = slice.len();
+        &mut slice[len - n..]
+    }
+
+    #[inline(never)]
+    fn partition(&mut self) {
+        // Reserve space for an additional L layers when needed.
+        let layer = self.pivots.len();
+        if layer + 2 * S::L >= self.pivots.capacity() {
+            self.pivots.reserve(S::L);
+        }
+
+        // The active layer borrows all of `self`.
+        let mut rng = core::mem::take(&mut self.rng);
+        let mut pivot_strategy = core::mem::take(&mut self.pivot_strategy);
+        pivot_strategy.on_split::<T, S>(self.pivots.last().copied());
+        let cur_layer = self.active_layer();
+        let n = cur_layer.len();
+        let (pivot, pivot_pos) = pivot_strategy.pick::<T, S>(cur_layer, &mut rng);
+        let large = simd::partition_in_place::<T, S, false>(cur_layer, pivot, pivot_pos);
+        pivot_strategy.observe(large.min(n - large) as f64 / n as f64, n);
+        self.rng = rng;
+        self.pivot_strategy = pivot_strategy;
+
+        // If all elements went down because the pivot was the largest one, try again.
+        if large == 0 {
+            return;
+        }
+        self.pivots.push(pivot);
+        self.tree.sync(&self.pivots);
+        self.sizes[layer] = large;
+        self.sizes.push(n - large);
+    }
+}
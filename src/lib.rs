@@ -5,6 +5,9 @@
 //! This is a _min_-queue, so `pop` returns the _smallest_ element in the queue.
 //!
 //! The [`ConfigurableSimdQuickHeap`] type is mostly for benchmarking only, to test various parameters.
+//! To choose the parameters at runtime instead, use a [`QuickHeapBuilder`].
+//!
+//! The [`CompactSimdQuickHeap`] stores all elements in a single array, for when memory is tight.
 //!
 //! By default, it uses AVX2, or AVX-512 when available during compile time.
 //! To force one or the other, use `SimdQuickHeap<T, Avx2>` or `SimdQuickHeap<T, Avx512>`.
@@ -24,17 +27,44 @@
 //! assert_eq!(q.pop(), Some(7));
 //! assert_eq!(q.pop(), None);
 //! ```
+//!
+//! ## `no_std`
+//! The crate only needs `alloc`. Disable the default `std` feature to use it without `std`.
+//! On bare-metal targets (`target_os = "none"`) it then only links `core` and `alloc`.
+//! Elsewhere it still links `std`, for the panic handler and allocator of the `cdylib`
+//! with the C bindings.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+#[macro_use]
+extern crate std;
+// Cargo builds the `cdylib` along with the `rlib`, also for dependents, and it needs a panic
+// handler and a global allocator. rustc drops it on targets without dynamic linking, such as
+// most bare-metal ones.
+#[cfg(not(any(feature = "std", test, target_os = "none")))]
+extern crate std as _;
 
 #[cfg(feature = "c")]
 #[doc(hidden)]
 pub mod c;
 
+pub mod bucket_alloc;
+
+pub mod rng;
+
 #[doc(hidden)]
 pub mod pivot_strategies;
 
 #[doc(hidden)]
 pub mod rebalancing_strategies;
 
+#[doc(hidden)]
+pub mod splitter_strategies;
+
+mod builder;
+mod compact;
 mod simd;
 #[cfg(test)]
 mod test;
@@ -46,10 +76,13 @@ mod test;
 use std::{any::type_name, time::Instant};
 
 #[cfg(feature = "pivots")]
-use std::cmp;
+use core::cmp;
 
+use alloc::{vec, vec::Vec};
+pub use builder::{Backend, DynQuickHeap, QuickHeapBuilder};
+pub use compact::CompactSimdQuickHeap;
+use core::marker::PhantomData;
 pub use simd::{Avx2, Avx512};
-use std::marker::PhantomData;
 
 /// Tag to use with [`ConfigurableSimdQuickHeap`] to use AVX-512 if it is available.
 #[cfg(not(target_feature = "avx512f"))]
@@ -67,8 +100,23 @@ impl<T: Copy + Ord> Elem for T {}
 /// For now, this means you can only use `u32`, `i32`, `u64`, and `i64`.
 pub use simd::SimdElem;
 
+use crate::pivot_strategies::PivotStrategy;
 use crate::rebalancing_strategies::NoRebalancing;
 
+/// The default layer size in bytes from which partitioning prefetches.
+const STREAM_THRESHOLD: usize = 16 << 20;
+
+/// Pass as `N` to [`ConfigurableSimdQuickHeap`] to set the bottom layer size at runtime,
+/// with [`set_bottom_threshold`](ConfigurableSimdQuickHeap::set_bottom_threshold).
+pub const DYNAMIC_N: usize = 0;
+
+/// The bottom layer size of heaps with [`DYNAMIC_N`] until it is set.
+const DEFAULT_N: usize = 32;
+
+/// After this many consecutive bad splits, a layer is split around the median of a larger
+/// sample, and after twice as many around the [`MedianOfMedians`](pivot_strategies::MedianOfMedians).
+const BAD_SPLITS: u8 = 2;
+
 // TODO:
 // mod buckets;
 
@@ -96,15 +144,25 @@ impl TotalPerformance {
 /// - `T`: the element type.
 /// - `S`: the SIMD tag: [`Avx2`] or [`Avx512`]. Default AVX-512 if available.
 /// - `P`: the pivoting strategy; see [`pivot_strategies`]. Default median of 3.
-/// - `N`: partition until the bottom layer is <N. Default `16`.
+/// - `R`: the rebalancing strategy; see [`rebalancing_strategies`].
+///   Default [`NoRebalancing`], which compiles to nothing.
+/// - `N`: partition until the bottom layer is <N. Default `32`.
+///   Pushes into the bottom layer use a SIMD sorted insert, so 64 is viable as well.
+///   With [`DYNAMIC_N`], the size is set at runtime instead, at the cost of a load per check.
 /// - `SORT`: whether to keep the bottom layer sorted. Default `true`.
+/// - `Sp`: the splitter strategy for multi-way partitioning of large layers;
+///   see [`splitter_strategies`]. Default [`BinarySplit`](splitter_strategies::BinarySplit).
+/// - `A`: the allocation backend of the layers; see [`bucket_alloc`].
+///   Default [`Global`](bucket_alloc::Global); large heaps may prefer [`HugePages`](bucket_alloc::HugePages).
 pub struct ConfigurableSimdQuickHeap<
     T: Elem,
     S: simd::SimdElem<T> = Simd,
     P: pivot_strategies::PivotStrategy = pivot_strategies::MedianOfM<3>,
     R: rebalancing_strategies::RebalancingStrategy<T> = rebalancing_strategies::NoRebalancing,
-    const N: usize = 16,
+    const N: usize = 32,
     const SORT: bool = true,
+    Sp: splitter_strategies::SplitterStrategy = splitter_strategies::BinarySplit,
+    A: bucket_alloc::BucketAlloc = bucket_alloc::Global,
 > {
     /// A decreasing array of the pivots for all layers.
     /// buckets[i] >= pivots[i] >= buckets[i+1]
@@ -114,22 +172,41 @@ pub struct ConfigurableSimdQuickHeap<
     /// The effective number of layers is always 1 longer than `pivots`.
     ///
     /// This will have enough underlying capacity for out-of-bounds SIMD reads.
-    pivots: Vec<T>,
+    pivots: bucket_alloc::Bucket<T, A>,
+    /// A summary of `pivots` to speed up `push` with many layers.
+    tree: simd::PivotTree<T, A>,
     /// The values in each layer.
     /// pivots[i-1] >= elements of buckets[i] >= pivots[i]
     /// Values equal to pivots[i] can be in layer i or i-1.
     ///
     /// This can be longer than `layer` to reuse allocations.
-    buckets: Vec<Vec<T>>,
+    buckets: Vec<bucket_alloc::Bucket<T, A>>,
+    /// Layers of at least this many bytes are partitioned with prefetching.
+    stream_threshold: usize,
+    /// The bottom layer size when `N` is [`DYNAMIC_N`].
+    threshold: usize,
+    /// Samples the pivots and splitters.
+    rng: rng::Rng,
+    /// The number of consecutive bad splits that led to each layer.
+    /// A split is bad when less than 1/8 of the layer ends up on one side.
+    bad_splits: Vec<u8>,
+    /// Whether all elements of each layer are equal, so that `pop` does not partition it.
+    /// This is as long as `buckets`.
+    all_equal: Vec<bool>,
 
     size: usize,
-    #[allow(dead_code)]
-    rebal_iteration: usize,
+    /// The number of pushes and pops so far, when the rebalancing strategy is enabled.
+    clock: rebalancing_strategies::Clock,
+    /// The clock at the creation of each layer, when the rebalancing strategy is enabled.
+    born: Vec<rebalancing_strategies::Clock>,
     #[allow(dead_code)]
     perf: TotalPerformance,
 
-    _p: PhantomData<P>,
-    _r: PhantomData<R>,
+    /// Picks the pivots, and learns from how well they split their layers.
+    pivot_strategy: P,
+    /// Merges or splits layers, based on their statistics.
+    rebalancing: R,
+    _sp: PhantomData<Sp>,
     _backend: PhantomData<S>,
 }
 
@@ -141,29 +218,22 @@ pub struct ConfigurableSimdQuickHeap<
 ///
 /// Uses AVX-512 instructions when available at compile time.
 pub type SimdQuickHeap<T> =
-    ConfigurableSimdQuickHeap<T, Simd, pivot_strategies::MedianOfM<3>, NoRebalancing, 16, true>;
+    ConfigurableSimdQuickHeap<T, Simd, pivot_strategies::MedianOfM<3>, NoRebalancing, 32, true>;
 
 /// Return a default instance with plenty (128) layers of empty buckets.
 impl<
     T: Elem,
     S: simd::SimdElem<T>,
     P: pivot_strategies::PivotStrategy,
-    R: rebalancing_strategies::RebalancingStrategy<T>,
+    R: rebalancing_strategies::RebalancingStrategy<T> + Default,
     const N: usize,
     const SORT: bool,
-> Default for ConfigurableSimdQuickHeap<T, S, P, R, N, SORT>
+    Sp: splitter_strategies::SplitterStrategy,
+    A: bucket_alloc::BucketAlloc + Default,
+> Default for ConfigurableSimdQuickHeap<T, S, P, R, N, SORT, Sp, A>
 {
     fn default() -> Self {
-        Self {
-            pivots: Vec::with_capacity(128),
-            buckets: (0..128).map(|_| vec![]).collect(),
-            size: 0,
-            rebal_iteration: 0,
-            perf: TotalPerformance::default(),
-            _p: PhantomData,
-            _r: PhantomData,
-            _backend: PhantomData,
-        }
+        Self::new_in(A::default())
     }
 }
 
@@ -174,8 +244,121 @@ impl<
     P: pivot_strategies::PivotStrategy,
     const N: usize,
     const SORT: bool,
-> ConfigurableSimdQuickHeap<T, S, P, R, N, SORT>
+    Sp: splitter_strategies::SplitterStrategy,
+    A: bucket_alloc::BucketAlloc,
+> ConfigurableSimdQuickHeap<T, S, P, R, N, SORT, Sp, A>
 {
+    /// Return an instance with plenty (128) layers of empty buckets, whose layers and
+    /// pivots allocate through `alloc`.
+    ///
+    /// Only the small per-layer bookkeeping uses the global allocator.
+    pub fn new_in(alloc: A) -> Self
+    where
+        R: Default,
+    {
+        Self::with_rebalancing_in(R::default(), alloc)
+    }
+
+    /// Return an empty heap that rebalances its layers with `rebalancing`, such as a
+    /// `Box<dyn RebalancingStrategy<T>>` chosen at runtime.
+    pub fn with_rebalancing(rebalancing: R) -> Self
+    where
+        A: Default,
+    {
+        Self::with_rebalancing_in(rebalancing, A::default())
+    }
+
+    /// Like [`with_rebalancing`](Self::with_rebalancing), with layers and pivots that allocate
+    /// through `alloc`.
+    pub fn with_rebalancing_in(rebalancing: R, alloc: A) -> Self {
+        let mut pivots = bucket_alloc::Bucket::new_in(alloc.clone());
+        pivots.reserve(128);
+        Self {
+            pivots,
+            tree: simd::PivotTree::new_in(alloc.clone()),
+            buckets: (0..128)
+                .map(|_| bucket_alloc::Bucket::new_in(alloc.clone()))
+                .collect(),
+            stream_threshold: STREAM_THRESHOLD,
+            threshold: DEFAULT_N,
+            rng: rng::Rng::default(),
+            bad_splits: Vec::new(),
+            all_equal: vec![false; 128],
+            size: 0,
+            clock: Default::default(),
+            born: Vec::new(),
+            perf: TotalPerformance::default(),
+            pivot_strategy: P::default(),
+            rebalancing,
+            _sp: PhantomData,
+            _backend: PhantomData,
+        }
+    }
+
+    /// Return an empty heap whose pivots are sampled with a PRNG seeded by `seed`,
+    /// so that its layout and timing can be replayed exactly.
+    pub fn with_seed(seed: u64) -> Self
+    where
+        R: Default,
+        A: Default,
+    {
+        Self {
+            rng: rng::Rng::new(seed),
+            ..Self::default()
+        }
+    }
+
+    /// Return an empty heap whose layers are separated by `hints` from the start,
+    /// so that pushes are classified into these layers instead of all going into the top one.
+    ///
+    /// The hints do not have to be sorted. Hints that turn out to leave a layer empty are
+    /// dropped by [`pop`](Self::pop), and layers that turn out too large are split further as usual.
+    pub fn with_pivot_hints(hints: &[T]) -> Self
+    where
+        R: Default,
+        A: Default,
+    {
+        let mut hints = hints.to_vec();
+        hints.sort_unstable_by(|a, b| b.cmp(a));
+        hints.dedup();
+        let mut heap = Self::default();
+        heap.pivots.extend_from_slice(&hints);
+        let layers = heap.pivots.len() + 1;
+        heap.pivots.reserve(2 * S::L);
+        if heap.buckets.len() < layers {
+            let alloc = heap.pivots.allocator();
+            heap.buckets
+                .resize_with(layers, || bucket_alloc::Bucket::new_in(alloc.clone()));
+            heap.all_equal.resize(layers, false);
+        }
+        heap.tree.rebuild(&heap.pivots);
+        heap
+    }
+
+    /// Remove all elements, and forget the layers.
+    ///
+    /// The allocations of the layers are kept.
+    pub fn clear(&mut self) {
+        self.clear_keep_pivots();
+        self.pivots.clear();
+        self.tree.rebuild(&self.pivots);
+    }
+
+    /// Remove all elements, but keep the current pivots as hints for the next pushes,
+    /// as with [`with_pivot_hints`](Self::with_pivot_hints).
+    ///
+    /// This is useful when the heap is refilled with similar keys, as in repeated
+    /// Dijkstra queries on the same graph.
+    pub fn clear_keep_pivots(&mut self) {
+        for layer in &mut self.buckets {
+            layer.clear();
+        }
+        self.bad_splits.fill(0);
+        self.all_equal.fill(false);
+        self.born.fill(self.clock);
+        self.size = 0;
+    }
+
     /// Return the total capacity over all buckets.
     pub fn capacity(&self) -> usize {
         self.buckets.iter().map(|b| b.capacity()).sum()
@@ -191,27 +374,44 @@ impl<
         self.size == 0
     }
 
+    /// Partition layers of at least `bytes` bytes with software prefetching.
+    ///
+    /// This should be around the size of the last-level cache. Default 16 MiB.
+    pub fn set_stream_threshold(&mut self, bytes: usize) {
+        self.stream_threshold = bytes;
+    }
+
+    /// Partition until the bottom layer has at most `n` elements.
+    ///
+    /// The best size depends on the element type, the SIMD backend and `SORT`.
+    /// Panics unless `N` is [`DYNAMIC_N`], or when `n` is 0.
+    pub fn set_bottom_threshold(&mut self, n: usize) {
+        assert!(N == DYNAMIC_N, "N is fixed at compile time");
+        assert!(n > 0, "the bottom layer cannot be split to 0 elements");
+        self.threshold = n;
+        // A grown bottom layer must be sorted from now on.
+        let layer = self.pivots.len();
+        if SORT && self.buckets[layer].len() <= n && !self.all_equal[layer] {
+            S::sort_desc(&mut self.buckets[layer]);
+        }
+    }
+
+    /// The size until which the bottom layer is partitioned.
+    #[inline(always)]
+    fn n(&self) -> usize {
+        if N == DYNAMIC_N { self.threshold } else { N }
+    }
+
     /// Push `t` onto the heap.
     pub fn push(&mut self, t: T) {
         // #[cfg(any(feature = "pivots", feature = "rebalancing"))]
         // let now = Instant::now();
 
-        #[cfg(feature = "rebalancing")] // TODO: Is this the right position here?
-        R::on_push(self.size, &mut self.pivots, &mut self.buckets);
+        self.on_push(1);
 
-        let target_layer = simd::push_position::<T, S>(&self.pivots, t);
-        let layer = &mut self.buckets[target_layer];
-        layer.reserve(S::L + 1);
-        if SORT && target_layer == self.pivots.len() && layer.len() < N {
-            // Count the number of larger elements in the prefix and insert the new element after them.
-            let pos = layer.partition_point(|&x| x > t);
-            layer.insert(pos, t);
-            // TODO: SIMD
-        } else {
-            layer.push(t);
-        }
-
-        self.size += 1;
+        let target_layer = simd::push_position::<T, S, A>(&self.pivots, &self.tree, t);
+        self.buckets[target_layer].reserve(S::L + 1);
+        self.insert(target_layer, t);
 
         // #[cfg(any(feature = "pivots", feature = "rebalancing"))]
         // {
@@ -221,51 +421,166 @@ impl<
         // }
     }
 
+    /// Push `t` onto the heap, or return an error and leave the heap unchanged
+    /// when its layer cannot grow.
+    ///
+    /// This does not call the rebalancing strategy, which may allocate.
+    /// The push still counts towards its next call.
+    pub fn try_push(&mut self, t: T) -> Result<(), bucket_alloc::AllocError> {
+        let target_layer = simd::push_position::<T, S, A>(&self.pivots, &self.tree, t);
+        self.buckets[target_layer].try_reserve(S::L + 1)?;
+        if self.rebalancing.enabled() {
+            self.clock.pushes += 1;
+        }
+        self.insert(target_layer, t);
+        Ok(())
+    }
+
+    /// Reserve room in every layer, so that the next `additional` pushes do not allocate.
+    ///
+    /// Splitting layers in [`pop`](Self::pop) may still allocate, and so may the rebalancing
+    /// strategy in [`push`](Self::push), for example when [`PushSplitting`] splits a layer.
+    /// [`try_push`](Self::try_push) does not call the strategy.
+    ///
+    /// [`PushSplitting`]: rebalancing_strategies::PushSplitting
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), bucket_alloc::AllocError> {
+        let additional = additional
+            .checked_add(S::L)
+            .ok_or(bucket_alloc::AllocError)?;
+        for layer in &mut self.buckets[..=self.pivots.len()] {
+            layer.try_reserve(additional)?;
+        }
+        Ok(())
+    }
+
+    /// Insert `t` into `target_layer`, which has room for `L` more elements.
+    #[inline(always)]
+    fn insert(&mut self, target_layer: usize, t: T) {
+        self.pivot_strategy.on_push::<T, S>(t);
+        let n = self.n();
+        let layer = &mut self.buckets[target_layer];
+        if self.all_equal[target_layer] && layer.first().is_some_and(|&x| x != t) {
+            self.all_equal[target_layer] = false;
+        }
+        if SORT && target_layer == self.pivots.len() && layer.len() < n {
+            simd::insert_sorted::<T, S, A>(layer, t);
+        } else {
+            layer.push(t);
+        }
+        self.size += 1;
+    }
+
+    /// Push all elements of `ts` onto the heap.
+    ///
+    /// Equivalent to pushing them one by one, but classifies `L` elements at a time
+    /// and appends them to their layers with compress-stores.
+    pub fn push_batch(&mut self, ts: &[T]) {
+        let bottom = self.pivots.len();
+        let bottom_len = self.buckets[bottom].len();
+        // Remember the lengths of the all-equal layers, to check what gets appended to them.
+        let equal_lens: Vec<(usize, usize)> = (0..=bottom)
+            .filter(|&i| self.all_equal[i])
+            .map(|i| (i, self.buckets[i].len()))
+            .collect();
+        simd::push_batch::<T, S, A>(&self.pivots, &self.tree, &mut self.buckets, ts);
+        for &t in ts {
+            self.pivot_strategy.on_push::<T, S>(t);
+        }
+        for (i, len) in equal_lens {
+            let layer = &self.buckets[i];
+            if layer
+                .first()
+                .is_some_and(|&x| layer[len..].iter().any(|&y| y != x))
+            {
+                self.all_equal[i] = false;
+            }
+        }
+
+        // The new elements were appended to the bottom layer unsorted.
+        let n = self.n();
+        let layer = &mut self.buckets[bottom];
+        let added = layer.len() - bottom_len;
+        if SORT && layer.len() <= n && added > 0 {
+            if added <= 4 {
+                // Insert a few new elements into the sorted layer one by one.
+                let mut new = [layer[bottom_len]; 4];
+                new[..added].copy_from_slice(&layer[bottom_len..]);
+                layer.truncate(bottom_len);
+                layer.reserve(added + S::L);
+                for &t in &new[..added] {
+                    simd::insert_sorted::<T, S, A>(layer, t);
+                }
+            } else {
+                S::sort_desc(layer);
+            }
+        }
+
+        self.size += ts.len();
+
+        // Rebalance after the batch, so that the strategy sees the layers it grew.
+        self.on_push(ts.len());
+    }
+
     /// Pop the smallest element from the queue.
     pub fn pop(&mut self) -> Option<T> {
         // #[cfg(any(feature = "pivots", feature = "rebalancing"))]
         // let now = Instant::now();
 
-        #[cfg(feature = "rebalancing")]
-        {
-            self.rebal_iteration += 1;
-        }
-
-        let layer = self.pivots.len();
-        // Only the top layer can be empty.
-        if layer == 0 && self.buckets[0].is_empty() {
+        if self.size == 0 {
             return None;
         }
-        // Split the current layer as long as it is too large.
-        if self.buckets[self.pivots.len()].len() > N {
-            while self.buckets[self.pivots.len()].len() > N {
+        if self.rebalancing.enabled() {
+            self.clock.pops += 1;
+        }
+        // Drop the empty layers that are left over from pivot hints.
+        if self.buckets[self.pivots.len()].is_empty() {
+            while self.buckets[self.pivots.len()].is_empty() {
+                self.pivots.pop();
+            }
+            self.tree.sync(&self.pivots);
+            if SORT && self.buckets[self.pivots.len()].len() <= self.n() {
+                let layer = &mut self.buckets[self.pivots.len()];
+                S::sort_desc(layer);
+            }
+        }
+        // Split the current layer as long as it is too large, unless all its elements are equal.
+        let splittable = |q: &Self| {
+            let layer = q.pivots.len();
+            q.buckets[layer].len() > q.n() && !q.all_equal[layer]
+        };
+        if splittable(self) {
+            while splittable(self) {
                 self.partition();
             }
-            if SORT {
+            if SORT && !self.all_equal[self.pivots.len()] {
                 // Sort final layer decreasing.
                 let layer = &mut self.buckets[self.pivots.len()];
-                layer.sort_unstable_by_key(|&x| std::cmp::Reverse(x));
+                S::sort_desc(layer);
             }
         }
         // Find and extract the minimum.
         let layer = &mut self.buckets[self.pivots.len()];
-        let min = if SORT {
+        let min = if SORT || self.all_equal[self.pivots.len()] {
             layer.pop().unwrap()
         } else {
-            let min_pos = simd::position_min::<T, S>(layer);
+            let min_pos = S::position_min(layer);
             layer.swap_remove(min_pos)
         };
+        self.pivot_strategy.on_pop::<T, S>(min);
 
         // Update the active layer.
-        if layer.is_empty() && self.pivots.len() > 0 {
+        if layer.is_empty() && !self.pivots.is_empty() {
+            // Give up its part of an allocation shared with the layers above.
+            layer.clear();
             self.pivots.pop();
+            self.tree.sync(&self.pivots);
             // assert!(self.buckets[self.pivots.len() + 1].is_empty());
             // self.buckets.pop();
 
             // Sort the new final layer decreasing if it's already small.
-            if SORT && self.buckets[self.pivots.len()].len() <= N {
+            if SORT && self.buckets[self.pivots.len()].len() <= self.n() {
                 let layer = &mut self.buckets[self.pivots.len()];
-                layer.sort_unstable_by_key(|&x| std::cmp::Reverse(x));
+                S::sort_desc(layer);
             }
         }
 
@@ -292,19 +607,114 @@ impl<
 
     #[inline(never)]
     fn partition(&mut self) {
-        #[cfg(all(feature = "pivots", not(feature = "time_only")))]
-        print!("\"{}\",", type_name::<P>());
-
         #[cfg(all(feature = "rebalancing", not(feature = "time_only")))]
         let now = Instant::now();
 
+        let layer = self.pivots.len();
+        let ways = Sp::ways(self.buckets[layer].len()).min(256);
+        let multiway = ways > 2 && self.partition_multiway(ways);
+        if !multiway {
+            #[cfg(all(feature = "pivots", not(feature = "time_only")))]
+            print!("\"{}\",", type_name::<P>());
+
+            self.partition_binary();
+        }
+        self.tree.sync(&self.pivots);
+
+        // The pivot statistics only cover binary splits.
+        #[cfg(all(feature = "pivots", not(feature = "time_only")))]
+        if !multiway {
+            let cur_len = self.buckets[layer].len();
+            let next_len = self.buckets[layer + 1].len();
+            let total_len = cur_len + next_len;
+
+            println!(
+                "{},{}",
+                total_len,
+                cmp::min(cur_len, next_len) as f64 / total_len as f64
+            );
+        }
+
+        if !self.rebalancing.enabled() {
+            return;
+        }
+        self.born.resize(self.buckets.len(), self.clock);
+        self.born[layer + 1..=self.pivots.len()].fill(self.clock);
+        if self.rebalance(false) {
+            #[cfg(all(feature = "rebalancing", not(feature = "time_only")))]
+            {
+                let elapsed = now.elapsed();
+
+                println!(
+                    "\"{}\",{},{},{}",
+                    type_name::<R>(),
+                    self.size,
+                    self.pivots.len(),
+                    elapsed.as_nanos()
+                );
+            }
+        }
+    }
+
+    /// Count `count` pushes and let the rebalancing strategy see the layers.
+    #[inline(always)]
+    fn on_push(&mut self, count: usize) {
+        if self.rebalancing.enabled() {
+            self.clock.pushes += count as u64;
+            self.rebalance(true);
+        }
+    }
+
+    /// Let the rebalancing strategy change the layers before a push or after a partition,
+    /// and return whether it did.
+    fn rebalance(&mut self, push: bool) -> bool {
+        self.born.resize(self.buckets.len(), self.clock);
+        let mut storage = rebalancing_strategies::Buckets {
+            pivots: &mut self.pivots,
+            buckets: &mut self.buckets,
+        };
+        let mut layers = rebalancing_strategies::Layers {
+            storage: &mut storage,
+            born: &mut self.born,
+            all_equal: &mut self.all_equal,
+            bad_splits: &mut self.bad_splits,
+            now: self.clock,
+            size: self.size,
+            rng: &mut self.rng,
+            partition: simd::partition_in_place::<T, S, false>,
+        };
+        let changed = if push {
+            self.rebalancing.on_push(&mut layers)
+        } else {
+            self.rebalancing.on_pop(&mut layers)
+        };
+        if !changed {
+            return false;
+        }
+        self.tree.rebuild(&self.pivots);
+        // Merging into the bottom layer can leave it small but unsorted.
+        let n = self.n();
+        let layer = &mut self.buckets[self.pivots.len()];
+        if SORT && layer.len() <= n {
+            S::sort_desc(layer);
+        }
+        true
+    }
+
+    /// Split the bottom layer in two around a pivot from the pivot strategy.
+    fn partition_binary(&mut self) {
         // Reserve space for an additional L layers when needed.
         let layer = self.pivots.len();
         if layer + 2 * S::L >= self.pivots.capacity() {
             self.pivots.reserve(S::L);
         }
         if layer + 1 == self.buckets.len() {
-            self.buckets.push(vec![]);
+            let alloc = self.pivots.allocator().clone();
+            self.buckets.push(bucket_alloc::Bucket::new_in(alloc));
+            self.all_equal.push(false);
+        }
+        if self.bad_splits.len() < layer + 2 {
+            self.bad_splits.resize(layer + 2, 0);
         }
         // Alias the current layer (to be split) and the next layer.
         let [cur_layer, next_layer] = &mut self.buckets[layer..=layer + 1] else {
@@ -315,7 +725,19 @@ impl<
         // Sample a pivot using the pivot strategy
         #[cfg(all(feature = "pivots", not(feature = "time_only")))]
         let start = Instant::now();
-        let (pivot, pivot_pos) = P::pick(&cur_layer);
+        // Escalate to better pivots when the previous splits were bad.
+        let bad = self.bad_splits[layer];
+        let (pivot, pivot_pos) = if bad < BAD_SPLITS {
+            let upper = self.pivots.last().copied();
+            self.pivot_strategy.on_split::<T, S>(upper);
+            self.pivot_strategy.pick::<T, S>(cur_layer, &mut self.rng)
+        } else if bad < 2 * BAD_SPLITS {
+            // A sample of about `4 log2 n` elements.
+            let m = 4 * (n.ilog2() as usize + 1) + 1;
+            pivot_strategies::get_m_median(cur_layer, m, &mut self.rng)
+        } else {
+            pivot_strategies::MedianOfMedians.pick::<T, S>(cur_layer, &mut self.rng)
+        };
 
         #[cfg(all(feature = "pivots", not(feature = "time_only")))]
         {
@@ -323,115 +745,161 @@ impl<
             print!("{},", elapsed.as_nanos());
         }
 
+        // After a bad split, which may be caused by many duplicates, separate the equal elements.
+        if bad > 0 {
+            self.partition_fat(pivot, bad);
+            return;
+        }
+
         self.pivots.push(pivot);
+        self.all_equal[layer + 1] = false;
 
-        // Reserve space in the next layer,
-        // and make sure the current layer can hold a spare SIMD register.
-        cur_layer.reserve(S::L);
-        next_layer.clear();
-        next_layer.reserve(n + S::L);
-
-        unsafe { cur_layer.set_len(n + S::L) };
-        unsafe { next_layer.set_len(n + S::L) };
-
-        let n2 = n.next_multiple_of(S::L).saturating_sub(S::L);
-
-        // Partition a list into two using SIMD.
-        let mut cur_len = 0;
-        let mut next_len = 0;
-        let half = (pivot_pos + 1).min(n2).next_multiple_of(S::L);
-        let threshold = S::splat(pivot);
-        for i in (0..half).step_by(S::L) {
-            unsafe {
-                S::partition_fast::<true>(
-                    S::simd_from_slice(cur_layer.get_unchecked(i..i + S::L)),
-                    threshold,
-                    cur_layer,
-                    &mut cur_len,
-                    next_layer,
-                    &mut next_len,
-                );
-            }
-        }
-        for i in (half..n2).step_by(S::L) {
-            unsafe {
-                S::partition_fast::<false>(
-                    S::simd_from_slice(cur_layer.get_unchecked(i..i + S::L)),
-                    threshold,
-                    cur_layer,
-                    &mut cur_len,
-                    next_layer,
-                    &mut next_len,
-                );
-            }
+        // Partition the layer in place, with the small elements at the back.
+        let prefetch = n * size_of::<T>() >= self.stream_threshold;
+        let cur_len = if prefetch {
+            simd::partition_in_place::<T, S, true>(cur_layer, pivot, pivot_pos)
+        } else {
+            simd::partition_in_place::<T, S, false>(cur_layer, pivot, pivot_pos)
+        };
+        let next_len = n - cur_len;
+        debug_assert!(next_len > 0);
+        // Only learn from the splits around pivots that the pivot strategy picked.
+        if bad < BAD_SPLITS {
+            self.pivot_strategy
+                .observe(cur_len.min(next_len) as f64 / n as f64, n);
         }
-        if n2 < n {
-            let threshold = if pivot_pos >= n2 {
-                S::splat(S::wrapping_add_one(pivot))
+
+        if cur_len == 0 {
+            // If all elements went down because the pivot was the largest one,
+            // undo and try again.
+            // The median of medians only fails on many duplicates, so go back to sampling then.
+            self.pivots.pop().unwrap();
+            self.bad_splits[layer] = if bad < 2 * BAD_SPLITS {
+                bad + 1
             } else {
-                S::splat(pivot)
+                BAD_SPLITS
             };
-            unsafe {
-                S::partition_slow(
-                    S::simd_from_slice(cur_layer.get_unchecked(n2..n2 + S::L)),
-                    S::splat(S::from_usize(n - n2)),
-                    threshold,
-                    cur_layer,
-                    &mut cur_len,
-                    next_layer,
-                    &mut next_len,
-                );
-            }
+            return;
         }
 
-        debug_assert!(next_len > 0);
+        let bad = if cur_len.min(next_len) < n / 8 {
+            bad.saturating_add(1)
+        } else {
+            0
+        };
+        self.bad_splits[layer] = bad;
+        self.bad_splits[layer + 1] = bad;
+
+        // Hand off the small elements to the next layer, which takes over the rest of the
+        // allocation.
+        *next_layer = cur_layer.split_off(cur_len);
+    }
 
-        unsafe {
-            cur_layer.set_len(cur_len);
-            next_layer.set_len(next_len);
+    /// Split the bottom layer `ways` ways around splitters from the splitter strategy.
+    ///
+    /// Returns `false` without changes when there are fewer than two distinct splitters.
+    fn partition_multiway(&mut self, ways: usize) -> bool {
+        let layer = self.pivots.len();
+        let mut splitters = Vec::with_capacity(ways);
+        Sp::pick(&self.buckets[layer], ways, &mut splitters, &mut self.rng);
+        // A single splitter may be the minimum, which would leave the new bottom layer empty.
+        if splitters.len() < 2 {
+            return false;
         }
+        let k = splitters.len() + 1;
 
-        // If we extracted all elements to the next layer
-        // because the pivot was the largest one,
-        // undo and try again.
-        if cur_len == 0 {
-            std::mem::swap(cur_layer, next_layer);
-            self.pivots.pop().unwrap();
+        // Reserve space for the new layers.
+        if layer + k + 2 * S::L >= self.pivots.capacity() {
+            self.pivots.reserve(k + S::L);
+        }
+        if self.buckets.len() < layer + k {
+            let alloc = self.pivots.allocator();
+            self.buckets
+                .resize_with(layer + k, || bucket_alloc::Bucket::new_in(alloc.clone()));
+            self.all_equal.resize(layer + k, false);
         }
+        let [cur_layer, new_layers @ ..] = &mut self.buckets[layer..layer + k] else {
+            unreachable!()
+        };
+        simd::partition_multiway::<T, S, A>(cur_layer, &splitters, new_layers);
 
-        #[cfg(all(feature = "pivots", not(feature = "time_only")))]
-        {
-            let cur_len = cur_layer.len();
-            let next_len = next_layer.len();
-            let total_len = cur_len + next_len;
+        // Each splitter is in the layer above it, so only the new bottom layer can be empty.
+        if new_layers[k - 2].is_empty() {
+            splitters.pop();
+        }
+        self.pivots.extend_from_slice(&splitters);
+        if self.bad_splits.len() < layer + k {
+            self.bad_splits.resize(layer + k, 0);
+        }
+        self.bad_splits[layer..layer + k].fill(0);
+        self.all_equal[layer..layer + k].fill(false);
+        true
+    }
 
-            print!(
-                "{},{}\n",
-                total_len,
-                cmp::min(cur_len, next_len) as f64 / total_len as f64
-            );
+    /// Split the bottom layer into the elements larger than, equal to, and smaller than `pivot`,
+    /// with the equal ones in a layer of their own that is marked as all-equal.
+    ///
+    /// `bad` is the number of bad splits that led to this layer.
+    fn partition_fat(&mut self, pivot: T, bad: u8) {
+        // Reserve space for up to two new layers.
+        let layer = self.pivots.len();
+        if layer + 2 * S::L + 1 >= self.pivots.capacity() {
+            self.pivots.reserve(S::L);
+        }
+        if self.buckets.len() < layer + 3 {
+            let alloc = self.pivots.allocator();
+            self.buckets
+                .resize_with(layer + 3, || bucket_alloc::Bucket::new_in(alloc.clone()));
+            self.all_equal.resize(layer + 3, false);
+        }
+        if self.bad_splits.len() < layer + 3 {
+            self.bad_splits.resize(layer + 3, 0);
         }
 
-        #[cfg(feature = "rebalancing")]
-        {
-            if self.rebal_iteration < R::MAX_REBAL_ITERATIONS {
-                return;
-            }
-            self.rebal_iteration = 0;
-            R::on_pop(self.size, &mut self.pivots, &mut self.buckets);
+        let n = self.buckets[layer].len();
+        let [cur_layer, equal_layer, small_layer] = &mut self.buckets[layer..layer + 3] else {
+            unreachable!()
+        };
+        let equal = simd::partition_three_way::<T, S, A>(cur_layer, pivot, small_layer);
+        let large = cur_layer.len();
+        let small = small_layer.len();
+        if bad < BAD_SPLITS {
+            self.pivot_strategy
+                .observe(large.min(n - large) as f64 / n as f64, n);
         }
 
-        #[cfg(all(feature = "rebalancing", not(feature = "time_only")))]
-        {
-            let elapsed = now.elapsed();
-
-            print!(
-                "\"{}\",{},{},{}\n",
-                type_name::<R>(),
-                self.size,
-                self.pivots.len(),
-                elapsed.as_nanos()
-            );
+        // Keep the non-empty parts as consecutive layers, with a pivot between each two.
+        // The equal part is empty when the pivot is not in the layer.
+        let bad = if large.max(small) > n - n / 8 {
+            bad.saturating_add(1)
+        } else {
+            0
+        };
+        let mut next = layer;
+        if large > 0 {
+            self.bad_splits[next] = bad;
+            next += 1;
+        }
+        if equal > 0 {
+            if large > 0 {
+                equal_layer.clear();
+                equal_layer.resize(equal, pivot);
+            } else {
+                cur_layer.resize(equal, pivot);
+            }
+            self.all_equal[next] = true;
+            self.bad_splits[next] = 0;
+            next += 1;
+        }
+        if small > 0 {
+            self.buckets.swap(next, layer + 2);
+            self.all_equal[next] = false;
+            // Equal elements are never split again, so only the large and small parts count.
+            self.bad_splits[next] = bad;
+            next += 1;
+        }
+        for _ in layer + 1..next {
+            self.pivots.push(pivot);
         }
     }
 }
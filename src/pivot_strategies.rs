@@ -1,14 +1,36 @@
-use crate::Elem;
+use alloc::{boxed::Box, vec::Vec};
 
-pub trait PivotStrategy {
-    fn pick<T: Elem>(layer: &Vec<T>) -> (T, usize);
+use crate::{Elem, SimdElem, rng::Rng, simd};
+
+/// Picks the pivots to split layers around. Each heap owns an instance.
+pub trait PivotStrategy: Default {
+    /// Return a pivot for the non-empty `layer`, and its position.
+    ///
+    /// Elements equal to the pivot go down when their SIMD block starts at or before the
+    /// position. At least one element must go down, so the pivot does not have to be in the
+    /// layer when it is at least the minimum and the position is the last one.
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize);
+
+    /// Called after a layer of `layer_len` elements was split around the last picked pivot,
+    /// where `split_ratio` is the fraction of the elements on the smaller side.
+    fn observe(&mut self, _split_ratio: f64, _layer_len: usize) {}
+
+    /// Called with each pushed element.
+    fn on_push<T: Elem, S: SimdElem<T>>(&mut self, _t: T) {}
+
+    /// Called with each popped element.
+    fn on_pop<T: Elem, S: SimdElem<T>>(&mut self, _t: T) {}
+
+    /// Called before `pick` with the pivot above the layer, if any,
+    /// which bounds the elements of the layer from above.
+    fn on_split<T: Elem, S: SimdElem<T>>(&mut self, _upper: Option<T>) {}
 }
 
-fn get_m_median<T: Elem>(layer: &Vec<T>, mut m: usize) -> (T, usize) {
+pub(crate) fn get_m_median<T: Elem>(layer: &[T], mut m: usize, rng: &mut Rng) -> (T, usize) {
     #[cfg(feature = "pivots")]
     print!("{},", m);
 
-    if m % 2 == 0 {
+    if m.is_multiple_of(2) {
         m += 1;
     }
     let n = layer.len();
@@ -16,7 +38,7 @@ fn get_m_median<T: Elem>(layer: &Vec<T>, mut m: usize) -> (T, usize) {
 
     let mut pivots: Vec<(T, usize)> = (0..m)
         .map(|_| {
-            let pos = rand::random_range(0..n);
+            let pos = rng.random_range(0..n);
             (layer[pos], pos)
         })
         .collect();
@@ -28,15 +50,15 @@ fn get_m_median<T: Elem>(layer: &Vec<T>, mut m: usize) -> (T, usize) {
     (pivot, pivot_pos)
 }
 
-fn get_median<T: Elem, const M: usize>(layer: &Vec<T>) -> (T, usize) {
+fn get_median<T: Elem, const M: usize>(layer: &[T], rng: &mut Rng) -> (T, usize) {
     assert!(M % 2 == 1, "M must be odd");
     #[cfg(feature = "pivots")]
     print!("{},", M);
     let n = layer.len();
     let k: usize = M / 2;
 
-    let mut pivots: [(T, usize); M] = std::array::from_fn(|_| {
-        let pos = rand::random_range(0..n);
+    let mut pivots: [(T, usize); M] = core::array::from_fn(|_| {
+        let pos = rng.random_range(0..n);
         (layer[pos], pos)
     });
 
@@ -47,23 +69,26 @@ fn get_median<T: Elem, const M: usize>(layer: &Vec<T>) -> (T, usize) {
     (pivot, pivot_pos)
 }
 
+#[derive(Default)]
 pub struct MedianOfM<const M: usize>;
 impl<const M: usize> PivotStrategy for MedianOfM<M> {
-    fn pick<T: Elem>(layer: &Vec<T>) -> (T, usize) {
-        get_median::<T, M>(layer)
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
+        get_median::<T, M>(layer, rng)
     }
 }
 
+#[derive(Default)]
 pub struct RandomPivot;
 impl PivotStrategy for RandomPivot {
-    fn pick<T: Elem>(layer: &Vec<T>) -> (T, usize) {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
         let n = layer.len();
-        let pivot_pos = rand::random_range(0..n);
+        let pivot_pos = rng.random_range(0..n);
         let pivot = layer[pivot_pos];
         (pivot, pivot_pos)
     }
 }
 
+#[derive(Default)]
 pub struct CbrtPivot<const A: usize, const B: usize>;
 
 impl<const A: usize, const B: usize> CbrtPivot<A, B> {
@@ -74,30 +99,32 @@ impl<const A: usize, const B: usize> CbrtPivot<A, B> {
 }
 
 impl<const A: usize, const B: usize> PivotStrategy for CbrtPivot<A, B> {
-    fn pick<T: Elem>(layer: &Vec<T>) -> (T, usize) {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
         let n = layer.len();
         let idx = size_of::<T>() * 8 - n.leading_zeros() as usize;
 
         let cbrt = CbrtPivot::<A, B>::CBRT_LOOKUP[idx];
-        let fac: f64 = 1 as f64 / A as f64;
+        let fac: f64 = 1.0 / A as f64;
 
         let m = (fac * cbrt as f64) as usize + B;
 
-        get_m_median(layer, m)
+        get_m_median(layer, m, rng)
     }
 }
 
+#[derive(Default)]
 pub struct Log2Pivot<const A: usize, const B: usize>;
 impl<const A: usize, const B: usize> PivotStrategy for Log2Pivot<A, B> {
-    fn pick<T: Elem>(layer: &Vec<T>) -> (T, usize) {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
         let n = layer.len();
         let idx = size_of::<T>() * 8 - n.leading_zeros() as usize;
         let m = A * idx + B;
 
-        get_m_median(layer, m)
+        get_m_median(layer, m, rng)
     }
 }
 
+#[derive(Default)]
 pub struct TablePivot;
 impl TablePivot {
     const LOOKUP: [usize; 32] = [
@@ -107,11 +134,360 @@ impl TablePivot {
 }
 
 impl PivotStrategy for TablePivot {
-    fn pick<T: Elem>(layer: &Vec<T>) -> (T, usize) {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
         let i = size_of::<T>() * 8 - layer.len().leading_zeros() as usize;
         if i > 31 {
-            return get_m_median(layer, 47);
+            return get_m_median(layer, 47, rng);
+        }
+        get_m_median(layer, TablePivot::LOOKUP[i], rng)
+    }
+}
+
+/// The exact median of the medians of groups of 5, which has at least 3/10 of the layer on
+/// either side (barring duplicates). Deterministic, but reads the entire layer.
+#[derive(Clone, Default)]
+pub struct MedianOfMedians;
+impl PivotStrategy for MedianOfMedians {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], _: &mut Rng) -> (T, usize) {
+        let mut medians: Vec<(T, usize)> = layer
+            .chunks(5)
+            .enumerate()
+            .map(|(i, group)| {
+                let mut sorted: [(T, usize); 5] =
+                    core::array::from_fn(|j| (group[j.min(group.len() - 1)], 5 * i + j));
+                let sorted = &mut sorted[..group.len()];
+                sorted.sort_unstable();
+                sorted[sorted.len() / 2]
+            })
+            .collect();
+        let k = medians.len() / 2;
+        medians.select_nth_unstable(k);
+        medians[k]
+    }
+}
+
+/// Learns the sample size for each layer size online, instead of using a table tuned offline.
+///
+/// A split with imbalance `d = 1/2 - split_ratio` costs about `1/H(1/2 - d) - 1 ≈ 2.9 d²` extra
+/// passes over the layer, where `H` is the binary entropy.
+/// Each sampled element costs about as much as partitioning `COST` elements.
+/// The sample size of a layer size grows while its average extra passes cost more than the
+/// sample, and shrinks when they cost less than a quarter of it.
+#[derive(Clone)]
+pub struct AdaptivePivot<const COST: usize = 64> {
+    /// The (odd) sample size for each bit length of the layer size.
+    m: [usize; 65],
+    /// The exponential moving average of the squared imbalance for each bit length.
+    imbalance: [f64; 65],
+}
+
+impl<const COST: usize> AdaptivePivot<COST> {
+    const MAX_M: usize = 4095;
+
+    /// Return the current sample size for layers of `layer_len` elements.
+    pub fn sample_size(&self, layer_len: usize) -> usize {
+        self.m[(usize::BITS - layer_len.leading_zeros()) as usize]
+    }
+}
+
+impl<const COST: usize> Default for AdaptivePivot<COST> {
+    fn default() -> Self {
+        Self {
+            m: [3; 65],
+            imbalance: [0.0; 65],
+        }
+    }
+}
+
+impl<const COST: usize> PivotStrategy for AdaptivePivot<COST> {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
+        get_m_median(layer, self.sample_size(layer.len()), rng)
+    }
+
+    fn observe(&mut self, split_ratio: f64, layer_len: usize) {
+        let i = (usize::BITS - layer_len.leading_zeros()) as usize;
+        let d = 0.5 - split_ratio;
+        self.imbalance[i] += (d * d - self.imbalance[i]) / 8.0;
+
+        let waste = 2.9 * self.imbalance[i] * layer_len as f64;
+        let cost = (self.m[i] * COST) as f64;
+        if waste > cost && self.m[i] < Self::MAX_M {
+            self.m[i] += 2;
+        } else if 4.0 * waste < cost && self.m[i] > 1 {
+            self.m[i] -= 2;
+        }
+    }
+}
+
+/// The midpoint of the minimum and maximum of the layer, which splits roughly uniform integer
+/// keys nearly perfectly without sampling.
+///
+/// After a midpoint split with less than 1/8 of the layer on one side, the next picks use
+/// `MedianOfM<M>` instead. Their number doubles with each consecutive skewed midpoint split.
+#[derive(Clone)]
+pub struct RangeMidpointPivot<const M: usize = 3> {
+    /// Whether the last pivot was a midpoint.
+    midpoint: bool,
+    /// The number of upcoming picks that use `MedianOfM<M>`.
+    fallback: usize,
+    /// The number of picks that the next skewed midpoint split falls back for.
+    backoff: usize,
+}
+
+impl<const M: usize> Default for RangeMidpointPivot<M> {
+    fn default() -> Self {
+        Self {
+            midpoint: false,
+            fallback: 0,
+            backoff: 1,
+        }
+    }
+}
+
+impl<const M: usize> PivotStrategy for RangeMidpointPivot<M> {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
+        if self.fallback > 0 {
+            self.fallback -= 1;
+            self.midpoint = false;
+            return MedianOfM::<M>.pick::<T, S>(layer, rng);
+        }
+        self.midpoint = true;
+        let (min, max) = simd::min_max::<T, S>(layer);
+        // All elements up to the midpoint go down, which includes the minimum.
+        (S::midpoint(min, max), layer.len() - 1)
+    }
+
+    fn observe(&mut self, split_ratio: f64, _layer_len: usize) {
+        if !self.midpoint {
+            return;
+        }
+        if split_ratio < 1.0 / 8.0 {
+            self.fallback = self.backoff;
+            self.backoff = (2 * self.backoff).min(1 << 10);
+        } else {
+            self.backoff = 1;
+        }
+    }
+}
+
+/// Splits at radix boundaries relative to the last popped key, like the buckets of a radix heap.
+///
+/// Bucket `i > 0` of a radix heap holds the keys whose highest bit that differs from the last
+/// popped key is bit `i - 1`, and bucket 0 the keys equal to it.
+/// The pivot separates the bucket of the layer minimum from the higher ones.
+/// When the entire layer is in one bucket, it splits at the highest bit in which the minimum and
+/// maximum differ instead.
+/// Layers then cover power-of-two aligned ranges, which suits monotone integer workloads.
+#[derive(Clone, Default)]
+pub struct RadixPivot {
+    /// The last popped key, mapped by [`SimdElem::to_radix`].
+    last: u64,
+}
+
+impl PivotStrategy for RadixPivot {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], _: &mut Rng) -> (T, usize) {
+        let n = layer.len();
+        let (min, max) = simd::min_max::<T, S>(layer);
+        let (min, max) = (S::to_radix(min), S::to_radix(max));
+        // Keys below the last popped one are treated as if they were equal to it.
+        let last = self.last.min(min);
+
+        // The first key above the bucket of the minimum.
+        let end = if min == last {
+            last as u128 + 1
+        } else {
+            let h = u64::BITS - 1 - (last ^ min).leading_zeros();
+            ((last >> h >> 1) as u128 + 1) << (h + 1)
+        };
+        let boundary = if end <= max as u128 {
+            end as u64
+        } else if min == max {
+            // All elements go down.
+            return (S::from_radix(max), n - 1);
+        } else {
+            let h = u64::BITS - 1 - (min ^ max).leading_zeros();
+            ((min >> h) | 1) << h
+        };
+        // The keys below the boundary go down.
+        (S::from_radix(boundary - 1), n - 1)
+    }
+
+    fn on_pop<T: Elem, S: SimdElem<T>>(&mut self, t: T) {
+        self.last = S::to_radix(t);
+    }
+}
+
+/// Proposes the median of the pushed keys in the range of the layer, according to a quantile
+/// sketch of all pushes, instead of sampling the layer.
+/// The range is taken to be from the last popped key up to the pivot above the layer.
+///
+/// The sketch is a simplified KLL sketch: level `i` holds keys of weight `2^i`, and when a level
+/// reaches `K` keys, it is sorted and every other key moves up a level.
+/// The top level drops half its keys instead, so that the sketch follows a changing distribution.
+/// Layers with fewer than 8 sketched keys in their range use `MedianOfM<M>` instead, and so do
+/// layers where none of 4 elements spread over the layer is at most the proposed median.
+#[derive(Clone)]
+pub struct SketchPivot<const K: usize = 64, const LEVELS: usize = 8, const M: usize = 3> {
+    /// The sketched keys of each level, mapped by [`SimdElem::to_radix`].
+    levels: [Vec<u64>; LEVELS],
+    /// Alternates which half of a full level is kept.
+    odd: bool,
+    /// The last popped key, mapped by [`SimdElem::to_radix`].
+    last: u64,
+    /// The pivot above the layer to split, mapped by [`SimdElem::to_radix`].
+    upper: Option<u64>,
+    /// Scratch space for the weighted keys in the range of the layer.
+    keys: Vec<(u64, usize)>,
+}
+
+impl<const K: usize, const LEVELS: usize, const M: usize> Default for SketchPivot<K, LEVELS, M> {
+    fn default() -> Self {
+        Self {
+            levels: core::array::from_fn(|_| Vec::with_capacity(K)),
+            odd: false,
+            last: 0,
+            upper: None,
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl<const K: usize, const LEVELS: usize, const M: usize> SketchPivot<K, LEVELS, M> {
+    /// Halve all full levels, starting at the bottom.
+    fn compact(&mut self) {
+        let mut i = 0;
+        while self.levels[i].len() >= K {
+            let [level, up @ ..] = &mut self.levels[i..] else {
+                unreachable!()
+            };
+            level.sort_unstable();
+            let kept = level.iter().skip(self.odd as usize).step_by(2);
+            self.odd = !self.odd;
+            if let Some(up) = up.first_mut() {
+                up.extend(kept);
+                level.clear();
+                i += 1;
+            } else {
+                let kept: Vec<u64> = kept.copied().collect();
+                *level = kept;
+                break;
+            }
+        }
+    }
+}
+
+impl<const K: usize, const LEVELS: usize, const M: usize> PivotStrategy
+    for SketchPivot<K, LEVELS, M>
+{
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
+        // Keys equal to the pivot above may be in this layer, but splitting there would move
+        // all elements down.
+        let (lo, hi) = (
+            self.last,
+            self.upper.map_or(u64::MAX as u128 + 1, u128::from),
+        );
+        self.keys.clear();
+        for (i, level) in self.levels.iter().enumerate() {
+            self.keys.extend(
+                level
+                    .iter()
+                    .filter(|&&x| lo <= x && (x as u128) < hi)
+                    .map(|&x| (x, 1 << i)),
+            );
+        }
+        if self.keys.len() >= 8 {
+            self.keys.sort_unstable();
+            let total: usize = self.keys.iter().map(|k| k.1).sum();
+            let mut below = 0;
+            let (median, _) = *self
+                .keys
+                .iter()
+                .find(|k| {
+                    below += k.1;
+                    2 * below >= total
+                })
+                .unwrap();
+            // All elements up to the median go down, which must include at least one.
+            let n = layer.len();
+            if (0..4).any(|i| S::to_radix(layer[i * (n - 1) / 3]) <= median) {
+                return (S::from_radix(median), n - 1);
+            }
+        }
+        MedianOfM::<M>.pick::<T, S>(layer, rng)
+    }
+
+    fn on_push<T: Elem, S: SimdElem<T>>(&mut self, t: T) {
+        self.levels[0].push(S::to_radix(t));
+        if self.levels[0].len() >= K {
+            self.compact();
+        }
+    }
+
+    fn on_pop<T: Elem, S: SimdElem<T>>(&mut self, t: T) {
+        self.last = S::to_radix(t);
+    }
+
+    fn on_split<T: Elem, S: SimdElem<T>>(&mut self, upper: Option<T>) {
+        self.upper = upper.map(S::to_radix);
+    }
+}
+
+/// One of the strategies above, chosen at runtime, as for a
+/// [`QuickHeapBuilder`](crate::QuickHeapBuilder). Default the median of 3.
+#[derive(Clone)]
+pub enum DynPivot {
+    /// The median of a sample of the given size, as for `MedianOfM`.
+    MedianOf(usize),
+    MedianOfMedians,
+    Adaptive(Box<AdaptivePivot>),
+    RangeMidpoint(RangeMidpointPivot),
+    Radix(RadixPivot),
+    Sketch(Box<SketchPivot>),
+}
+
+impl Default for DynPivot {
+    fn default() -> Self {
+        Self::MedianOf(3)
+    }
+}
+
+impl PivotStrategy for DynPivot {
+    fn pick<T: Elem, S: SimdElem<T>>(&mut self, layer: &[T], rng: &mut Rng) -> (T, usize) {
+        match self {
+            Self::MedianOf(m) => get_m_median(layer, *m, rng),
+            Self::MedianOfMedians => MedianOfMedians.pick::<T, S>(layer, rng),
+            Self::Adaptive(p) => p.pick::<T, S>(layer, rng),
+            Self::RangeMidpoint(p) => p.pick::<T, S>(layer, rng),
+            Self::Radix(p) => p.pick::<T, S>(layer, rng),
+            Self::Sketch(p) => p.pick::<T, S>(layer, rng),
+        }
+    }
+
+    fn observe(&mut self, split_ratio: f64, layer_len: usize) {
+        match self {
+            Self::Adaptive(p) => p.observe(split_ratio, layer_len),
+            Self::RangeMidpoint(p) => p.observe(split_ratio, layer_len),
+            _ => {}
+        }
+    }
+
+    fn on_push<T: Elem, S: SimdElem<T>>(&mut self, t: T) {
+        if let Self::Sketch(p) = self {
+            p.on_push::<T, S>(t);
+        }
+    }
+
+    fn on_pop<T: Elem, S: SimdElem<T>>(&mut self, t: T) {
+        match self {
+            Self::Radix(p) => p.on_pop::<T, S>(t),
+            Self::Sketch(p) => p.on_pop::<T, S>(t),
+            _ => {}
+        }
+    }
+
+    fn on_split<T: Elem, S: SimdElem<T>>(&mut self, upper: Option<T>) {
+        if let Self::Sketch(p) = self {
+            p.on_split::<T, S>(upper);
         }
-        get_m_median(layer, TablePivot::LOOKUP[i])
     }
 }
@@ -1,69 +1,470 @@
-pub trait RebalancingStrategy<T> {
-    const MAX_REBAL_ITERATIONS: usize;
-    fn on_pop(size: usize, pivots: &mut Vec<T>, buckets: &mut Vec<Vec<T>>);
-    fn on_push(size: usize, pivots: &mut Vec<T>, buckets: &mut Vec<Vec<T>>);
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::bucket_alloc::{Bucket, BucketAlloc};
+use crate::rng::Rng;
+use crate::simd::MAX_L;
+
+/// Merges (or splits) layers to keep their number in check. Each heap owns an instance.
+///
+/// The trait is object-safe, so a heap can also hold a `Box<dyn RebalancingStrategy<T>>` that
+/// is chosen at runtime. The [`DynRebalancing`] enum does the same for the strategies here,
+/// without the virtual calls.
+pub trait RebalancingStrategy<T: Copy> {
+    /// Whether the heap calls the strategy at all.
+    /// When a strategy type always returns `false`, the calls and the layer statistics compile
+    /// to nothing.
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    /// Called after each partition in `pop`. Return whether the layers changed.
+    fn on_pop(&mut self, _layers: &mut Layers<'_, T>) -> bool {
+        false
+    }
+
+    /// Called before each push, and after each batch of pushes. Return whether the layers changed.
+    fn on_push(&mut self, _layers: &mut Layers<'_, T>) -> bool {
+        false
+    }
 }
 
+impl<T: Copy, R: RebalancingStrategy<T> + ?Sized> RebalancingStrategy<T> for Box<R> {
+    fn enabled(&self) -> bool {
+        (**self).enabled()
+    }
+
+    fn on_pop(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        (**self).on_pop(layers)
+    }
+
+    fn on_push(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        (**self).on_push(layers)
+    }
+}
+
+/// The number of pushes and pops of a heap, to measure the age of its layers.
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct Clock {
+    pub(crate) pushes: u64,
+    pub(crate) pops: u64,
+}
+
+/// The buckets and pivots of a heap, behind [`Layers`] so that it does not depend on the
+/// allocator of the heap.
+pub(crate) trait Storage<T: Copy> {
+    /// Return the number of layers.
+    fn count(&self) -> usize;
+
+    /// Return the number of buckets, including the spare ones.
+    fn buckets(&self) -> usize;
+
+    /// Return the number of elements in layer `i`.
+    fn len(&self, i: usize) -> usize;
+
+    /// Return the elements of layer `i`.
+    fn layer(&mut self, i: usize) -> &mut [T];
+
+    /// Append the smaller of layers `i` and `i + 1` to the larger one, which becomes layer `i`,
+    /// and remove the pivot between them.
+    fn merge(&mut self, i: usize);
+
+    /// Move the elements of layer `i` from `at` on into a new layer `i + 1`, below `pivot`.
+    fn split(&mut self, i: usize, at: usize, pivot: T);
+}
+
+/// The [`Storage`] of a heap whose buckets allocate through `A`.
+pub(crate) struct Buckets<'a, T: Copy, A: BucketAlloc> {
+    pub(crate) pivots: &'a mut Bucket<T, A>,
+    pub(crate) buckets: &'a mut Vec<Bucket<T, A>>,
+}
+
+impl<T: Copy, A: BucketAlloc> Storage<T> for Buckets<'_, T, A> {
+    fn count(&self) -> usize {
+        self.pivots.len() + 1
+    }
+
+    fn buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn len(&self, i: usize) -> usize {
+        self.buckets[i].len()
+    }
+
+    fn layer(&mut self, i: usize) -> &mut [T] {
+        &mut self.buckets[i]
+    }
+
+    fn merge(&mut self, i: usize) {
+        if self.buckets[i].len() < self.buckets[i + 1].len() {
+            self.buckets.swap(i, i + 1);
+        }
+        let mut smaller = self.buckets.remove(i + 1);
+        self.buckets[i].extend_from_slice(&smaller);
+        // Keep the emptied allocation for later layers.
+        smaller.clear();
+        self.buckets.push(smaller);
+        self.pivots.remove(i);
+    }
+
+    fn split(&mut self, i: usize, at: usize, pivot: T) {
+        let (large, small) = (at, self.buckets[i].len() - at);
+        // The smaller side moves to a new layer, in a spare allocation if there is one.
+        let mut lower = if self.buckets.len() > self.count() {
+            self.buckets.pop().unwrap()
+        } else {
+            Bucket::new_in(self.pivots.allocator().clone())
+        };
+        lower.clear();
+        let upper = &mut self.buckets[i];
+        if large >= small {
+            lower.extend_from_slice(&upper[large..]);
+            upper.truncate(large);
+        } else {
+            // Keep the small elements in this allocation, and move the last `large` of them
+            // into the gap left by the large ones.
+            lower.extend_from_slice(&upper[..large]);
+            upper.copy_within(small.., 0);
+            upper.truncate(small);
+            core::mem::swap(upper, &mut lower);
+        }
+        self.buckets.insert(i + 1, lower);
+
+        // Keep room for out-of-bounds SIMD reads.
+        if self.pivots.len() + 2 * MAX_L >= self.pivots.capacity() {
+            self.pivots.reserve(2 * MAX_L);
+        }
+        self.pivots.push(pivot);
+        self.pivots[i..].rotate_right(1);
+    }
+}
+
+/// The layers of a heap as seen by a [`RebalancingStrategy`], with statistics per layer.
+///
+/// Layer 0 holds the largest elements, and the bottom layer `count() - 1` the smallest ones.
+/// Changing the layers through [`merge`](Self::merge) and [`split`](Self::split) keeps the
+/// bookkeeping of the heap consistent.
+pub struct Layers<'a, T: Copy> {
+    pub(crate) storage: &'a mut dyn Storage<T>,
+    /// The clock at the creation of each layer. As long as the buckets.
+    pub(crate) born: &'a mut Vec<Clock>,
+    pub(crate) all_equal: &'a mut Vec<bool>,
+    pub(crate) bad_splits: &'a mut Vec<u8>,
+    pub(crate) now: Clock,
+    pub(crate) size: usize,
+    pub(crate) rng: &'a mut Rng,
+    /// Partitions a layer in place, as [`partition_in_place`](crate::simd::partition_in_place).
+    pub(crate) partition: fn(&mut [T], T, usize) -> usize,
+}
+
+impl<T: Copy> Layers<'_, T> {
+    /// Return the number of layers.
+    pub fn count(&self) -> usize {
+        self.storage.count()
+    }
+
+    /// Return the number of elements in the heap.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Return the number of elements in layer `i`.
+    pub fn len(&self, i: usize) -> usize {
+        self.storage.len(i)
+    }
+
+    /// Return the total number of pushes into the heap.
+    pub fn pushes(&self) -> u64 {
+        self.now.pushes
+    }
+
+    /// Return the total number of pops from the heap.
+    pub fn pops(&self) -> u64 {
+        self.now.pops
+    }
+
+    /// Return the number of pushes and pops since layer `i` was created.
+    pub fn age(&self, i: usize) -> u64 {
+        let born = self.born[i];
+        self.now.pushes + self.now.pops - born.pushes - born.pops
+    }
+
+    /// Return the number of pops since layer `i` was created.
+    pub fn layer_pops(&self, i: usize) -> u64 {
+        self.now.pops - self.born[i].pops
+    }
+
+    /// Merge layer `i + 1` into layer `i`, and forget the pivot between them.
+    ///
+    /// The smaller layer is appended to the larger one, whose allocation is kept.
+    pub fn merge(&mut self, i: usize) {
+        self.storage.merge(i);
+
+        let born = self.born.remove(i + 1);
+        self.born[i].pushes = self.born[i].pushes.min(born.pushes);
+        self.born[i].pops = self.born[i].pops.min(born.pops);
+        self.born.push(self.now);
+        self.all_equal.remove(i + 1);
+        self.all_equal[i] = false;
+        self.all_equal.push(false);
+        if self.bad_splits.len() > i + 1 {
+            self.bad_splits.remove(i + 1);
+            self.bad_splits[i] = 0;
+        }
+    }
+
+    /// Split layer `i` in two around the median of a small sample, and return whether it did.
+    /// The small elements become the new layer `i + 1`.
+    /// Layers whose elements are all equal, or that all fall on one side, are left as they are.
+    pub fn split(&mut self, i: usize) -> bool
+    where
+        T: Ord,
+    {
+        let n = self.storage.len(i);
+        if n < 2 || self.all_equal[i] {
+            return false;
+        }
+        let layer = self.storage.layer(i);
+        let mut sample: [(T, usize); 15] = core::array::from_fn(|_| {
+            let j = self.rng.random_range(0..n);
+            (layer[j], j)
+        });
+        let (_, &mut (pivot, pivot_pos), _) = sample.select_nth_unstable(7);
+        let large = (self.partition)(layer, pivot, pivot_pos);
+        if large == 0 {
+            return false;
+        }
+        self.storage.split(i, large, pivot);
+
+        let buckets = self.storage.buckets();
+        self.born.truncate(buckets - 1);
+        self.born.insert(i + 1, self.now);
+        self.all_equal.truncate(buckets - 1);
+        self.all_equal.insert(i + 1, false);
+        self.all_equal[i] = false;
+        if self.bad_splits.len() > i {
+            self.bad_splits.insert(i + 1, 0);
+            self.bad_splits[i] = 0;
+        }
+        true
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct NoRebalancing;
-impl<T> RebalancingStrategy<T> for NoRebalancing {
-    const MAX_REBAL_ITERATIONS: usize = usize::MAX;
-    fn on_pop(_: usize, _: &mut Vec<T>, _: &mut Vec<Vec<T>>) {}
-    fn on_push(_: usize, _: &mut Vec<T>, _: &mut Vec<Vec<T>>) {}
+impl<T: Copy> RebalancingStrategy<T> for NoRebalancing {
+    fn enabled(&self) -> bool {
+        false
+    }
 }
 
-pub struct NaiveLogRebalancing<const THRESH: usize, const IT: usize>;
+/// Merges all layers into one when there are more than `THRESH * log2(size)` of them,
+/// checked after partitions at most once every `IT` pops.
+#[derive(Clone, Default)]
+pub struct NaiveLogRebalancing<const THRESH: usize, const IT: usize> {
+    /// The number of pops at the last check.
+    last: u64,
+}
 impl<T: Copy, const THRESH: usize, const IT: usize> RebalancingStrategy<T>
     for NaiveLogRebalancing<THRESH, IT>
 {
-    const MAX_REBAL_ITERATIONS: usize = IT;
-    fn on_pop(size: usize, pivots: &mut Vec<T>, buckets: &mut Vec<Vec<T>>) {
-        let max = THRESH * size.ilog2() as usize;
+    fn on_pop(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        if layers.pops() < self.last + IT as u64 {
+            return false;
+        }
+        self.last = layers.pops();
+        let max = THRESH * layers.size().ilog2() as usize;
 
-        if pivots.len() > max {
-            pivots.clear();
+        if layers.count() - 1 > max {
             // Merge all layers together
-            let mut flat_buckets: Vec<T> = vec![];
-            for i in 0..buckets.len() {
-                let bucket = &mut buckets[i].clone();
-                flat_buckets.append(bucket);
+            while layers.count() > 1 {
+                layers.merge(0);
             }
-            buckets.clear();
-            buckets.push(flat_buckets);
-
-            debug_assert!(buckets.len() == 1);
-            debug_assert!(pivots.is_empty());
+            return true;
         }
+        false
     }
-
-    fn on_push(_: usize, _: &mut Vec<T>, _: &mut Vec<Vec<T>>) {}
 }
 
-pub struct PivotForgetting<const F: usize, const IT: usize>;
+/// Merges a layer with the one above it when they are small compared to the layers below,
+/// checked after partitions at most once every `IT` pops.
+#[derive(Clone, Default)]
+pub struct PivotForgetting<const F: usize, const IT: usize> {
+    /// The number of pops at the last check.
+    last: u64,
+}
 impl<T: Copy, const F: usize, const IT: usize> RebalancingStrategy<T> for PivotForgetting<F, IT> {
-    const MAX_REBAL_ITERATIONS: usize = IT;
-    fn on_pop(_: usize, pivots: &mut Vec<T>, buckets: &mut Vec<Vec<T>>) {
-        // Invariant: buckets[pivots.len()] contains the smallest elements
+    fn on_pop(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        if layers.pops() < self.last + IT as u64 {
+            return false;
+        }
+        self.last = layers.pops();
+        let count = layers.count();
+        // Invariant: the bottom layer contains the smallest elements
         let mut total: usize = 0;
-        let mut layer: usize = pivots.len();
+        let mut layer: usize = count - 1;
         loop {
-            if layer > 0 && buckets[layer].len() + buckets[layer - 1].len() < F * total {
+            if layer > 0 && layers.len(layer) + layers.len(layer - 1) < F * total {
                 // Merge bucket with next one, forget the pivot of the layer
-                if buckets[layer].len() > buckets[layer - 1].len() {
-                    let old_bucket = buckets.remove(layer - 1);
-                    buckets[layer - 1].extend(old_bucket);
-                } else {
-                    let old_bucket = buckets.remove(layer);
-                    buckets[layer - 1].extend(old_bucket);
-                }
-                pivots.remove(layer - 1);
+                layers.merge(layer - 1);
             } else if layer == 0 {
                 break;
             } else {
-                total += buckets[layer].len();
+                total += layers.len(layer);
+            }
+            layer -= 1;
+        }
+        layers.count() != count
+    }
+}
+
+/// Splits layers of more than `BUDGET` elements in two around a sampled median during pushes,
+/// so that a burst of pushes does not leave a huge layer for a later `pop` to partition.
+///
+/// Every `IT` pushes, the layers are checked from the top, and each push (or batch of pushes)
+/// splits at most one layer over the budget, carrying the rest of the check over to the next
+/// pushes. With single pushes, layers then stay below about `BUDGET + IT` elements, so a push
+/// splits at most that many, and each split of a layer is paid for by the roughly `BUDGET / 2`
+/// pushes that filled it since its last split. A batch of `k` pushes may leave a layer of up to
+/// about `BUDGET + IT + k` elements to split. `BUDGET` should be well above `IT` and `N`.
+/// Layers whose elements are all equal stay over the budget.
+#[derive(Clone, Default)]
+pub struct PushSplitting<const BUDGET: usize = { 1 << 16 }, const IT: usize = 64> {
+    /// The number of pushes at the last check.
+    last: u64,
+    /// The next layer to check, while a check is in progress.
+    next: Option<usize>,
+}
+impl<T: Copy + Ord, const BUDGET: usize, const IT: usize> RebalancingStrategy<T>
+    for PushSplitting<BUDGET, IT>
+{
+    fn on_push(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        let mut i = match self.next {
+            Some(i) => i,
+            None if layers.pushes() < self.last + IT as u64 => return false,
+            None => {
+                self.last = layers.pushes();
+                0
+            }
+        };
+        while i < layers.count() {
+            if layers.len(i) > BUDGET && layers.split(i) {
+                // Both halves may still be over the budget, so check this layer again next time.
+                self.next = Some(i);
+                return true;
+            }
+            i += 1;
+        }
+        self.next = None;
+        false
+    }
+}
+
+/// Merges two adjacent layers only when the expected savings of pushes exceed the cost,
+/// including the partition work that the merge adds for the pops.
+///
+/// Merging layers `i` and `i + 1`, of `u` and `l` elements, saves the pops nothing: with the
+/// pivot between them they partition each layer on its own, and after a merge they first have
+/// to separate the two again. That adds about `u log2((u + l) / u) + l log2((u + l) / l)`
+/// partitioned elements, which is `u + l` for layers of equal size but little for lopsided ones,
+/// and the merge itself moves the smaller layer.
+/// The savings come from the pushes instead: each pivot costs every push `1 / PIVOTS_PER_ELEMENT`
+/// of the cost of partitioning one element, until the pops reach the layers. Those pushes are
+/// estimated as the elements in the layers below, times the pushes per pop during the lifetime
+/// of layer `i + 1`.
+/// Checked after partitions and before pushes, at most once every `IT` operations.
+#[derive(Clone, Default)]
+pub struct CostModelRebalancing<const PIVOTS_PER_ELEMENT: usize = 16, const IT: usize = 256> {
+    /// The number of pushes and pops at the last check.
+    last: u64,
+}
+
+impl<const PIVOTS_PER_ELEMENT: usize, const IT: usize>
+    CostModelRebalancing<PIVOTS_PER_ELEMENT, IT>
+{
+    fn rebalance<T: Copy>(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        let now = layers.pushes() + layers.pops();
+        if now < self.last + IT as u64 {
+            return false;
+        }
+        self.last = now;
+
+        // The bottom layer is split again right away, so it is never merged.
+        let count = layers.count();
+        if count < 3 {
+            return false;
+        }
+        // The partition work of the pops to separate `part` from `other` after a merge.
+        let separate = |part: usize, other: usize| match part {
+            0 => 0,
+            _ => part * ((part + other) / part).ilog2() as usize,
+        };
+        let mut below = layers.len(count - 1);
+        let mut layer = count - 2;
+        while layer > 0 {
+            let (upper, lower) = (layers.len(layer - 1), layers.len(layer));
+            let pops = layers.layer_pops(layer);
+            let pushes = layers.age(layer) - pops;
+            let savings =
+                below as u128 * pushes as u128 / pops.max(1) as u128 / PIVOTS_PER_ELEMENT as u128;
+            let cost = (upper.min(lower) + separate(upper, lower) + separate(lower, upper)) as u128;
+            if savings > cost {
+                layers.merge(layer - 1);
+            } else {
+                below += lower;
             }
             layer -= 1;
         }
+        layers.count() != count
+    }
+}
+
+impl<T: Copy, const PIVOTS_PER_ELEMENT: usize, const IT: usize> RebalancingStrategy<T>
+    for CostModelRebalancing<PIVOTS_PER_ELEMENT, IT>
+{
+    fn on_pop(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        self.rebalance(layers)
+    }
+
+    fn on_push(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        self.rebalance(layers)
+    }
+}
+
+/// One of the strategies above, chosen at runtime, as for a
+/// [`QuickHeapBuilder`](crate::QuickHeapBuilder). Default none.
+#[derive(Clone, Default)]
+pub enum DynRebalancing {
+    #[default]
+    None,
+    NaiveLog(NaiveLogRebalancing<3, 128>),
+    PivotForgetting(PivotForgetting<2, 128>),
+    PushSplitting(PushSplitting),
+    CostModel(CostModelRebalancing),
+}
+
+impl<T: Copy + Ord> RebalancingStrategy<T> for DynRebalancing {
+    fn enabled(&self) -> bool {
+        !matches!(self, Self::None)
+    }
+
+    fn on_pop(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        match self {
+            Self::None => false,
+            Self::NaiveLog(r) => r.on_pop(layers),
+            Self::PivotForgetting(r) => r.on_pop(layers),
+            Self::PushSplitting(r) => r.on_pop(layers),
+            Self::CostModel(r) => r.on_pop(layers),
+        }
     }
 
-    fn on_push(_: usize, _: &mut Vec<T>, _: &mut Vec<Vec<T>>) {}
+    fn on_push(&mut self, layers: &mut Layers<'_, T>) -> bool {
+        match self {
+            Self::None => false,
+            Self::NaiveLog(r) => r.on_push(layers),
+            Self::PivotForgetting(r) => r.on_push(layers),
+            Self::PushSplitting(r) => r.on_push(layers),
+            Self::CostModel(r) => r.on_push(layers),
+        }
+    }
 }
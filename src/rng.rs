@@ -0,0 +1,43 @@
+//! A small PRNG for sampling pivots and splitters, so that the heap does not need `std`.
+
+use core::ops::Range;
+
+const INC: u64 = 0xa076_1d64_78bd_642f;
+const MIX: u64 = 0xe703_7ed1_a0b4_28db;
+
+/// The wyrand PRNG.
+///
+/// Each heap owns one, so that its layout and timing can be replayed exactly from its seed.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Return the next random `u64`.
+    #[inline(always)]
+    pub fn random_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(INC);
+        let t = self.0 as u128 * (self.0 ^ MIX) as u128;
+        (t >> 64) as u64 ^ t as u64
+    }
+
+    /// Return a uniform random value in the non-empty `range`.
+    #[inline(always)]
+    pub fn random_range(&mut self, range: Range<usize>) -> usize {
+        let n = range.end - range.start;
+        debug_assert!(n > 0);
+        range.start + ((self.random_u64() as u128 * n as u128) >> 64) as usize
+    }
+}
+
+/// Seeded with [`Rng::DEFAULT_SEED`].
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SEED)
+    }
+}
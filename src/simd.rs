@@ -1,6 +1,9 @@
-use std::mem::transmute;
+use alloc::{vec, vec::Vec};
+use core::{cmp::Ordering, mem::transmute};
 
-use wide::{CmpGt, CmpLt};
+use wide::{CmpEq, CmpGt, CmpLt};
+
+use crate::bucket_alloc::{Bucket, BucketAlloc};
 
 /// Marker type selecting the AVX2 (256-bit) SIMD backend for [`ConfigurableSimdQuickHeap`].
 ///
@@ -28,6 +31,8 @@ pub struct Avx512<const CS: bool = false>;
 pub trait SimdElem<T>: 'static {
     /// Number of SIMD lanes.
     const L: usize;
+    /// Minimum value for `T`.
+    const MIN: T;
     /// Maximum value for `T`.
     const MAX: T;
     /// The SIMD vector type (e.g. `i32x8` or `i64x4`).
@@ -39,10 +44,39 @@ pub trait SimdElem<T>: 'static {
     unsafe fn simd_from_slice(slice: &[T]) -> Self::Simd;
     /// Returns bitmask where bit `i` = `a[i] <= b[i]`.
     fn simd_lt_bitmask(a: Self::Simd, b: Self::Simd) -> u64;
+    /// Returns bitmask where bit `i` = `a[i] == b[i]`.
+    fn simd_eq_bitmask(a: Self::Simd, b: Self::Simd) -> u64;
+    /// Lane-wise minimum.
+    fn simd_min(a: Self::Simd, b: Self::Simd) -> Self::Simd;
+    /// Lane-wise maximum.
+    fn simd_max(a: Self::Simd, b: Self::Simd) -> Self::Simd;
+    /// Returns the lanes of `t` where bit `i` of `mask` is set, and the lanes of `f` elsewhere.
+    fn simd_select(mask: u64, t: Self::Simd, f: Self::Simd) -> Self::Simd;
+    /// Returns lane `i ^ j` in lane `i`, for `j < L`.
+    fn simd_permute_xor(a: Self::Simd, j: usize) -> Self::Simd;
+    /// Returns the lanes of `t` where `a[i] < b[i]`, and the lanes of `f` elsewhere.
+    fn simd_lt_select(a: Self::Simd, b: Self::Simd, t: Self::Simd, f: Self::Simd) -> Self::Simd;
+    /// Returns lane `i`.
+    fn lane(a: Self::Simd, i: usize) -> T;
+    /// Minimum over all lanes.
+    fn reduce_min(a: Self::Simd) -> T;
+    /// Lane-wise wrapping addition.
+    fn simd_add(a: Self::Simd, b: Self::Simd) -> Self::Simd;
+    /// Returns `*base.add(idx[i])` in lane `i`.
+    /// # Safety
+    /// Each lane of `idx` must be an in-bounds index from `base`.
+    unsafe fn simd_gather(base: *const T, idx: Self::Simd) -> Self::Simd;
     /// Returns a SIMD register `[0, 1, 2, ..., L-1]`.
     fn lane_indices() -> Self::Simd;
     fn from_usize(n: usize) -> T;
+    fn to_usize(t: T) -> usize;
     fn wrapping_add_one(t: T) -> T;
+    /// The mean of `a` and `b`, rounded down.
+    fn midpoint(a: T, b: T) -> T;
+    /// Map `t` to a `u64` with the same order, for radix computations.
+    fn to_radix(t: T) -> u64;
+    /// The inverse of `to_radix`.
+    fn from_radix(r: u64) -> T;
 
     /// Partition all `L` lanes of `vals` against `threshold`.
     ///
@@ -73,82 +107,777 @@ pub trait SimdElem<T>: 'static {
         w: &mut [T],
         w_idx: &mut usize,
     );
+
+    /// Like `partition_fast`, but writes both halves into the same slice `v`:
+    /// large lanes forward starting at `lo`, and small lanes backward ending at `hi`.
+    ///
+    /// # Safety
+    /// `v[lo..lo + L]` and `v[hi - L..hi]` must not contain unread data.
+    unsafe fn partition_inplace<const EQUAL_DOWN: bool>(
+        vals: Self::Simd,
+        threshold: Self::Simd,
+        v: &mut [T],
+        lo: &mut usize,
+        hi: &mut usize,
+    );
+
+    /// Store `vals` at `ptr`, with `t` inserted in lane `k` and the lanes from `k` onwards
+    /// shifted up by one. The original last lane is dropped.
+    ///
+    /// # Safety
+    /// `ptr` must be valid for writing `L` elements, and `k < L`.
+    unsafe fn insert_store(vals: Self::Simd, t: T, k: usize, ptr: *mut T);
+
+    /// Store the lanes of `vals` whose bit in `mask` is set contiguously at `ptr`,
+    /// and return their number.
+    ///
+    /// # Safety
+    /// `ptr` must be valid for writing `L` elements.
+    unsafe fn compress_store(mask: u64, vals: Self::Simd, ptr: *mut T) -> usize;
+
+    /// Return the position of a minimum of `v`.
+    ///
+    /// Keeps the lane-wise minimum of all blocks together with the block it came from,
+    /// with the last block overlapping the one before it.
+    /// Then reduces the minimum and looks up the first lane that has it.
+    #[inline(always)]
+    fn position_min(v: &[T]) -> usize
+    where
+        T: Copy + Ord,
+    {
+        let n = v.len();
+        if n < Self::L {
+            return position_min_scalar(v);
+        }
+        let load = |i: usize| unsafe { Self::simd_from_slice(v.get_unchecked(i..)) };
+
+        let mut min = load(0);
+        let mut min_block = Self::splat(Self::from_usize(0));
+        let mut i = Self::L;
+        while i < n {
+            let i2 = i.min(n - Self::L);
+            let vals = load(i2);
+            min_block =
+                Self::simd_lt_select(vals, min, Self::splat(Self::from_usize(i2)), min_block);
+            min = Self::simd_min(vals, min);
+            i += Self::L;
+        }
+        let mask = Self::simd_eq_bitmask(min, Self::splat(Self::reduce_min(min)));
+        let lane = mask.trailing_zeros() as usize;
+        Self::to_usize(Self::lane(min_block, lane)) + lane
+    }
+
+    /// Sort `v` decreasing.
+    ///
+    /// Inputs of up to `8 L` elements are padded to a power of two number of registers
+    /// and sorted with a bitonic network; longer ones fall back to `sort_unstable`.
+    #[inline(always)]
+    fn sort_desc(v: &mut [T])
+    where
+        T: Copy + Ord,
+        Self: Sized,
+    {
+        match v.len().div_ceil(Self::L) {
+            0 => {}
+            1 => sort_desc_network::<T, Self, 1>(v),
+            2 => sort_desc_network::<T, Self, 2>(v),
+            3..=4 => sort_desc_network::<T, Self, 4>(v),
+            5..=8 => sort_desc_network::<T, Self, 8>(v),
+            _ => v.sort_unstable_by_key(|&x| core::cmp::Reverse(x)),
+        }
+    }
+}
+
+/// The largest number of lanes of any backend.
+pub(crate) const MAX_L: usize = 16;
+
+/// The number of consecutive SIMD blocks that [`partition_in_place`] reads from one side.
+const BLOCKS: usize = 4;
+
+/// How many bytes ahead of each read cursor [`partition_in_place`] prefetches.
+const PREFETCH_BYTES: usize = 1024;
+
+/// Partition `v` in place around `pivot`, and return the number of elements that stay up.
+///
+/// Afterwards, `v[..k]` contains the large elements and `v[k..]` the small ones.
+/// Elements equal to `pivot` go down when their SIMD block starts at or before `pivot_pos`,
+/// so that the pivot itself always goes down.
+///
+/// This is the two-ended partition of vectorized quicksort: the first and last few blocks are
+/// buffered, and each iteration reads the next `BLOCKS` blocks from the side with the least
+/// free space, so that the (full-width) writes never overwrite unread elements.
+///
+/// With `PREFETCH`, each iteration also prefetches `PREFETCH_BYTES` ahead of both read cursors,
+/// for layers that do not fit in cache.
+pub fn partition_in_place<T: Copy + Ord, S: SimdElem<T>, const PREFETCH: bool>(
+    v: &mut [T],
+    pivot: T,
+    pivot_pos: usize,
+) -> usize {
+    let n = v.len();
+    let l = S::L;
+    let c = BLOCKS * l;
+    let threshold = S::splat(pivot);
+
+    // Scratch space for the first and last `c` elements and the (less than `c`) remaining
+    // ones, with room for a full SIMD store.
+    let mut large = [pivot; 3 * BLOCKS * MAX_L + MAX_L];
+    let mut small = [pivot; 3 * BLOCKS * MAX_L + MAX_L];
+    let mut large_len = 0;
+    let mut small_len = 0;
+    // Partition a block of at most `L` elements starting at index `i` into the scratch space.
+    let mut buffer = |block: &[T], i: usize| {
+        if block.len() == l {
+            unsafe {
+                let vals = S::simd_from_slice(block);
+                if i <= pivot_pos {
+                    S::partition_fast::<true>(
+                        vals,
+                        threshold,
+                        &mut large,
+                        &mut large_len,
+                        &mut small,
+                        &mut small_len,
+                    );
+                } else {
+                    S::partition_fast::<false>(
+                        vals,
+                        threshold,
+                        &mut large,
+                        &mut large_len,
+                        &mut small,
+                        &mut small_len,
+                    );
+                }
+            }
+        } else {
+            for &x in block {
+                let is_small = if i <= pivot_pos {
+                    x <= pivot
+                } else {
+                    x < pivot
+                };
+                if is_small {
+                    small[small_len] = x;
+                    small_len += 1;
+                } else {
+                    large[large_len] = x;
+                    large_len += 1;
+                }
+            }
+        }
+    };
+
+    if n < 2 * c {
+        for (j, block) in v.chunks(l).enumerate() {
+            buffer(block, j * l);
+        }
+        v[..large_len].copy_from_slice(&large[..large_len]);
+        v[large_len..].copy_from_slice(&small[..small_len]);
+        return large_len;
+    }
+
+    let mut first = [pivot; BLOCKS * MAX_L];
+    let mut last = [pivot; BLOCKS * MAX_L];
+    first[..c].copy_from_slice(&v[..c]);
+    last[..c].copy_from_slice(&v[n - c..]);
+    let mut read_lo = c;
+    let mut read_hi = n - c;
+    let mut lo = 0;
+    let mut hi = n;
+    while read_hi - read_lo >= c {
+        let i = if read_lo - lo <= hi - read_hi {
+            read_lo += c;
+            read_lo - c
+        } else {
+            read_hi -= c;
+            read_hi
+        };
+        if PREFETCH {
+            unsafe {
+                use core::arch::x86_64::*;
+                let d = PREFETCH_BYTES / size_of::<T>();
+                let p = v.as_ptr();
+                _mm_prefetch::<_MM_HINT_T0>(p.wrapping_add(read_lo + d) as *const i8);
+                _mm_prefetch::<_MM_HINT_T0>(p.wrapping_add(read_hi).wrapping_sub(d) as *const i8);
+            }
+        }
+        // Load all blocks up front, so the reads do not depend on the writes.
+        let vals: [S::Simd; BLOCKS] =
+            core::array::from_fn(|j| unsafe { S::simd_from_slice(v.get_unchecked(i + j * l..)) });
+        for (j, vals) in vals.into_iter().enumerate() {
+            unsafe {
+                if i + j * l <= pivot_pos {
+                    S::partition_inplace::<true>(vals, threshold, v, &mut lo, &mut hi);
+                } else {
+                    S::partition_inplace::<false>(vals, threshold, v, &mut lo, &mut hi);
+                }
+            }
+        }
+    }
+
+    // The remaining unread elements, and the buffered blocks.
+    for (j, block) in v[read_lo..read_hi].chunks(l).enumerate() {
+        buffer(block, read_lo + j * l);
+    }
+    for (j, block) in first[..c].chunks(l).enumerate() {
+        buffer(block, j * l);
+    }
+    for (j, block) in last[..c].chunks(l).enumerate() {
+        buffer(block, n - c + j * l);
+    }
+    debug_assert_eq!(hi - lo, large_len + small_len);
+    v[lo..lo + large_len].copy_from_slice(&large[..large_len]);
+    v[lo + large_len..hi].copy_from_slice(&small[..small_len]);
+    lo + large_len
+}
+
+/// Return the minimum and maximum of the non-empty `v`.
+pub fn min_max<T: Copy + Ord, S: SimdElem<T>>(v: &[T]) -> (T, T) {
+    let mut mins = S::splat(v[0]);
+    let mut maxs = mins;
+    let mut blocks = v.chunks_exact(S::L);
+    for block in &mut blocks {
+        let vals = unsafe { S::simd_from_slice(block) };
+        mins = S::simd_min(mins, vals);
+        maxs = S::simd_max(maxs, vals);
+    }
+    let mut min = S::reduce_min(mins);
+    let mut max = (0..S::L).map(|i| S::lane(maxs, i)).max().unwrap();
+    for &x in blocks.remainder() {
+        min = min.min(x);
+        max = max.max(x);
+    }
+    (min, max)
+}
+
+/// Partition `v` around `pivot` into the elements larger than, equal to, and smaller than it,
+/// and return the number of equal elements.
+///
+/// Afterwards, `v` only contains the large elements, and the small ones replace `small`.
+/// The equal elements are only counted.
+///
+/// The large lanes of each block are compress-stored to the front of `v`, which only overwrites
+/// elements that were already read.
+pub fn partition_three_way<T: Copy + Ord, S: SimdElem<T>, A: BucketAlloc>(
+    v: &mut Bucket<T, A>,
+    pivot: T,
+    small: &mut Bucket<T, A>,
+) -> usize {
+    let n = v.len();
+    small.clear();
+    small.reserve(n + S::L);
+    let threshold = S::splat(pivot);
+    let all = (1u64 << S::L) - 1;
+
+    let mut large_len = 0;
+    let mut small_len = 0;
+    let mut equal = 0;
+    let mut i = 0;
+    unsafe {
+        while i + S::L <= n {
+            let vals = S::simd_from_slice(v.get_unchecked(i..));
+            let lt = S::simd_lt_bitmask(vals, threshold);
+            let eq = S::simd_eq_bitmask(vals, threshold);
+            large_len += S::compress_store(all & !(lt | eq), vals, v.as_mut_ptr().add(large_len));
+            small_len += S::compress_store(lt, vals, small.as_mut_ptr().add(small_len));
+            equal += eq.count_ones() as usize;
+            i += S::L;
+        }
+        small.set_len(small_len);
+    }
+    for i in i..n {
+        let x = v[i];
+        match x.cmp(&pivot) {
+            Ordering::Greater => {
+                v[large_len] = x;
+                large_len += 1;
+            }
+            Ordering::Equal => equal += 1,
+            Ordering::Less => small.push(x),
+        }
+    }
+    v.truncate(large_len);
+    equal
+}
+
+/// Split `v` into `splitters.len() + 1` layers in one pass, for decreasing `splitters`.
+///
+/// Element `x` goes to layer `i` when exactly `i` splitters are larger than `x`.
+/// Layer 0 stays in `v` and layer `i > 0` replaces `out[i - 1]`.
+///
+/// As in super-scalar sample sort, each block of `L` elements descends an implicit binary
+/// search tree of the splitters, with one gather and compare per level.
+/// The blocks are classified twice: first to count the layer sizes, so that the layers are
+/// allocated with their exact size, and then to append the lanes of each layer with a
+/// compress-store.
+pub fn partition_multiway<T: Copy + Ord, S: SimdElem<T>, A: BucketAlloc>(
+    v: &mut Bucket<T, A>,
+    splitters: &[T],
+    out: &mut [Bucket<T, A>],
+) {
+    let k = splitters.len() + 1;
+    assert!(k <= 256 && out.len() + 1 >= k);
+    debug_assert!(splitters.is_sorted_by(|a, b| a > b));
+
+    // The splitters in BFS order, padded with the minimum, which is never larger than `x`.
+    let depth = k.next_power_of_two().trailing_zeros();
+    let mut tree = vec![S::MIN; (1 << depth) - 1];
+    fill_tree(splitters, &mut tree, 0, &mut 0);
+    let leaves = tree.len();
+    let classify = |vals: S::Simd| {
+        let one = S::splat(S::from_usize(1));
+        let two = S::splat(S::from_usize(2));
+        // Go right, to the larger class, when the node is larger than the lane.
+        let mut node = S::splat(S::from_usize(0));
+        for _ in 0..depth {
+            let pivots = unsafe { S::simd_gather(tree.as_ptr(), node) };
+            let double = S::simd_add(node, node);
+            node = S::simd_lt_select(
+                vals,
+                pivots,
+                S::simd_add(double, two),
+                S::simd_add(double, one),
+            );
+        }
+        // The leaves are numbered from `leaves` onwards.
+        node
+    };
+
+    let mut counts = [0usize; 256];
+    let mut i = 0;
+    while i + S::L <= v.len() {
+        let vals = unsafe { S::simd_from_slice(&v[i..]) };
+        let class = classify(vals);
+        let mut todo = (1u64 << S::L) - 1;
+        while todo != 0 {
+            let c = S::lane(class, todo.trailing_zeros() as usize);
+            let mask = S::simd_eq_bitmask(class, S::splat(c)) & todo;
+            todo &= !mask;
+            counts[S::to_usize(c) - leaves] += mask.count_ones() as usize;
+        }
+        i += S::L;
+    }
+    for x in &v[i..] {
+        counts[splitters.partition_point(|s| s > x)] += 1;
+    }
+
+    for (layer, &count) in out.iter_mut().zip(&counts[1..k]) {
+        layer.clear();
+        layer.reserve(count + S::L);
+    }
+    // Layer 0 is written back to the front of `v`, which never passes the block being read.
+    let mut w = 0;
+    let mut i = 0;
+    while i + S::L <= v.len() {
+        let vals = unsafe { S::simd_from_slice(&v[i..]) };
+        let class = classify(vals);
+        let mut todo = (1u64 << S::L) - 1;
+        while todo != 0 {
+            let c = S::lane(class, todo.trailing_zeros() as usize);
+            let mask = S::simd_eq_bitmask(class, S::splat(c)) & todo;
+            todo &= !mask;
+            unsafe {
+                match S::to_usize(c) - leaves {
+                    0 => w += S::compress_store(mask, vals, v.as_mut_ptr().add(w)),
+                    c => {
+                        let layer = &mut out[c - 1];
+                        let len = layer.len();
+                        let count = S::compress_store(mask, vals, layer.as_mut_ptr().add(len));
+                        layer.set_len(len + count);
+                    }
+                }
+            }
+        }
+        i += S::L;
+    }
+    for r in i..v.len() {
+        let x = v[r];
+        match splitters.partition_point(|s| s > &x) {
+            0 => {
+                v[w] = x;
+                w += 1;
+            }
+            c => out[c - 1].push(x),
+        }
+    }
+    v.truncate(w);
+}
+
+/// Store the decreasing `sorted` into `tree` as an implicit binary search tree rooted at `node`,
+/// where the children of node `j` are `2j + 1` and `2j + 2`.
+/// Nodes past the end of `sorted` keep their value.
+fn fill_tree<T: Copy>(sorted: &[T], tree: &mut [T], node: usize, next: &mut usize) {
+    if node >= tree.len() {
+        return;
+    }
+    fill_tree(sorted, tree, 2 * node + 1, next);
+    if let Some(&s) = sorted.get(*next) {
+        tree[node] = s;
+    }
+    *next += 1;
+    fill_tree(sorted, tree, 2 * node + 2, next);
+}
+
+/// Insert `t` into `layer`, which is sorted decreasing.
+///
+/// Counts the larger elements with a compare and popcount per block,
+/// and then shifts the blocks from the insertion point onwards up by one lane.
+/// `layer` must have capacity for `L` elements beyond its length.
+#[inline(always)]
+pub fn insert_sorted<T: Copy + Ord, S: SimdElem<T>, A: BucketAlloc>(
+    layer: &mut Bucket<T, A>,
+    t: T,
+) {
+    let n = layer.len();
+    assert!(layer.capacity() >= n + S::L);
+    let t_simd = S::splat(t);
+    let ptr = layer.as_mut_ptr();
+
+    let mut pos = 0;
+    let mut i = 0;
+    while i < n {
+        // NOTE: This reads beyond the length but within the capacity.
+        let vals = unsafe { (ptr.add(i) as *const S::Simd).read_unaligned() };
+        let in_range = if n - i >= S::L {
+            u64::MAX
+        } else {
+            (1 << (n - i)) - 1
+        };
+        pos += (S::simd_lt_bitmask(t_simd, vals) & in_range).count_ones() as usize;
+        i += S::L;
+    }
+
+    let mut i = pos / S::L * S::L;
+    let mut k = pos - i;
+    let mut carry = t;
+    while i <= n {
+        unsafe {
+            let vals = (ptr.add(i) as *const S::Simd).read_unaligned();
+            // The last lane moves to the next block, if there is one.
+            let next = if i + S::L <= n {
+                *ptr.add(i + S::L - 1)
+            } else {
+                t
+            };
+            S::insert_store(vals, carry, k, ptr.add(i));
+            carry = next;
+        }
+        k = 0;
+        i += S::L;
+    }
+    unsafe { layer.set_len(n + 1) };
+}
+
+/// The number of entries summarized by each entry of the next level of a [`PivotTree`].
+const TREE_B: usize = 16;
+
+/// A multi-level summary of the pivots, so that [`push_position`] only scans a few blocks
+/// of `TREE_B` entries even with many layers.
+///
+/// Level 0 holds the last (smallest) pivot of each full block of `TREE_B` pivots,
+/// and each next level summarizes the one below it in the same way.
+/// The top level has fewer than `TREE_B` entries.
+///
+/// Each level has enough underlying capacity for out-of-bounds SIMD reads.
+#[derive(Debug)]
+pub struct PivotTree<T: Copy, A: BucketAlloc = crate::bucket_alloc::Global> {
+    levels: Vec<Bucket<T, A>>,
+    alloc: A,
+}
+
+impl<T: Copy, A: BucketAlloc + Default> Default for PivotTree<T, A> {
+    fn default() -> Self {
+        Self::new_in(A::default())
+    }
+}
+
+impl<T: Copy, A: BucketAlloc> PivotTree<T, A> {
+    /// Return an empty summary whose levels allocate through `alloc`.
+    pub fn new_in(alloc: A) -> Self {
+        Self {
+            levels: vec![],
+            alloc,
+        }
+    }
+
+    /// Update the summary after pivots were pushed or popped at the back.
+    pub fn sync(&mut self, pivots: &[T]) {
+        let mut h = 0;
+        loop {
+            let (below, above) = self.levels.split_at_mut(h);
+            let below = if h == 0 { pivots } else { &below[h - 1] };
+            let len = below.len() / TREE_B;
+            if len == 0 {
+                self.levels.truncate(h);
+                return;
+            }
+            if above.is_empty() {
+                self.levels.push(Bucket::new_in(self.alloc.clone()));
+                continue;
+            }
+            let level = &mut above[0];
+            level.truncate(len);
+            level.reserve(len + MAX_L - level.len());
+            while level.len() < len {
+                level.push(below[(level.len() + 1) * TREE_B - 1]);
+            }
+            h += 1;
+        }
+    }
+
+    /// Rebuild the summary after arbitrary changes to the pivots.
+    pub fn rebuild(&mut self, pivots: &[T]) {
+        for level in &mut self.levels {
+            level.clear();
+        }
+        self.sync(pivots);
+    }
+}
+
+/// Return the number of the decreasing `v[lo..hi]` that are larger than `t`.
+///
+/// NOTE: This reads beyond `hi`, but within the capacity of `v`.
+#[inline(always)]
+fn count_larger<T: Copy, S: SimdElem<T>, A: BucketAlloc>(
+    v: &Bucket<T, A>,
+    lo: usize,
+    hi: usize,
+    t: S::Simd,
+) -> usize {
+    let mut count = 0;
+    let mut i = lo;
+    while i < hi {
+        let vals = unsafe { (v.as_ptr().add(i) as *const S::Simd).read_unaligned() };
+        // TODO: Compare SIMD register against 0
+        count += S::simd_lt_bitmask(t, vals).trailing_ones() as usize;
+        i += S::L;
+    }
+    count.min(hi - lo)
 }
 
 #[inline(always)]
-pub fn push_position<T: Copy + Ord, S: SimdElem<T>>(pivots: &Vec<T>, t: T) -> usize {
+pub fn push_position<T: Copy + Ord, S: SimdElem<T>, A: BucketAlloc>(
+    pivots: &Bucket<T, A>,
+    tree: &PivotTree<T, A>,
+    t: T,
+) -> usize {
     // Baseline:
     // return pivots.iter().map(|x| (t <= **x) as usize).sum::<usize>();
 
-    if pivots.len() <= 64 {
-        let t_simd = S::splat(t);
+    let t_simd = S::splat(t);
+    let Some(top) = tree.levels.last() else {
+        return count_larger::<T, S, A>(pivots, 0, pivots.len(), t_simd);
+    };
 
-        let mut target_layer = 0;
-        let mut i = 0;
-        while i < pivots.len() {
-            // NOTE: This reads beyond the length but within the capacity.
-            let vals = unsafe { (pivots.as_ptr().add(i) as *const S::Simd).read_unaligned() };
-            // TODO: Compare SIMD register against 0
-            target_layer += S::simd_lt_bitmask(t_simd, vals).trailing_ones() as usize;
-            i += S::L;
+    // Find the first block of each level that is not entirely larger than `t`.
+    let mut block = count_larger::<T, S, A>(top, 0, top.len(), t_simd);
+    for level in tree.levels.iter().rev().skip(1).chain([pivots]) {
+        let lo = block * TREE_B;
+        let hi = (lo + TREE_B).min(level.len());
+        block = lo + count_larger::<T, S, A>(level, lo, hi, t_simd);
+    }
+    block
+}
+
+/// Up to this many pivots, [`push_batch`] classifies a block with one compare and blend per pivot.
+/// Beyond that, it looks up each lane with [`push_position`].
+const BLEND_PIVOTS: usize = 32;
+
+/// Append each element of `ts` to the layer given by [`push_position`].
+///
+/// Classifies `L` elements at a time, and then appends all lanes of each distinct layer in the
+/// block with a single compress-store.
+/// The order of the appended elements within a layer is unspecified.
+pub fn push_batch<T: Copy + Ord, S: SimdElem<T>, A: BucketAlloc>(
+    pivots: &Bucket<T, A>,
+    tree: &PivotTree<T, A>,
+    buckets: &mut [Bucket<T, A>],
+    ts: &[T],
+) {
+    if ts.len() < S::L {
+        for &t in ts {
+            buckets[push_position::<T, S, A>(pivots, tree, t)].push(t);
         }
-        target_layer.min(pivots.len())
-    } else {
+        return;
+    }
+
+    let splats: Vec<(S::Simd, S::Simd)> = if pivots.len() <= BLEND_PIVOTS {
         pivots
-            .binary_search_by(|p| {
-                if *p < t {
-                    std::cmp::Ordering::Greater
-                } else {
-                    std::cmp::Ordering::Less
+            .iter()
+            .enumerate()
+            .map(|(j, &p)| (S::splat(p), S::splat(S::from_usize(j + 1))))
+            .collect()
+    } else {
+        vec![]
+    };
+
+    let mut blocks = ts.chunks_exact(S::L);
+    for block in &mut blocks {
+        let vals = unsafe { S::simd_from_slice(block) };
+        let class = if pivots.len() <= BLEND_PIVOTS {
+            // The pivots are decreasing, so the last one that is larger than a lane is its layer.
+            let mut class = S::splat(S::from_usize(0));
+            for &(p, id) in &splats {
+                class = S::simd_lt_select(vals, p, id, class);
+            }
+            class
+        } else {
+            let mut layers = [S::from_usize(0); MAX_L];
+            for (layer, &t) in layers.iter_mut().zip(block) {
+                *layer = S::from_usize(push_position::<T, S, A>(pivots, tree, t));
+            }
+            unsafe { S::simd_from_slice(&layers) }
+        };
+
+        let mut todo = (1u64 << S::L) - 1;
+        while todo != 0 {
+            let c = S::lane(class, todo.trailing_zeros() as usize);
+            let mask = S::simd_eq_bitmask(class, S::splat(c)) & todo;
+            todo &= !mask;
+
+            let layer = &mut buckets[S::to_usize(c)];
+            layer.reserve(S::L);
+            unsafe {
+                let len = layer.len();
+                let count = S::compress_store(mask, vals, layer.as_mut_ptr().add(len));
+                layer.set_len(len + count);
+            }
+        }
+    }
+    for &t in blocks.remainder() {
+        buckets[push_position::<T, S, A>(pivots, tree, t)].push(t);
+    }
+}
+
+/// Sort the `v.len() <= K L` elements of `v` decreasing with a bitonic network on `K` registers.
+#[inline(always)]
+fn sort_desc_network<T: Copy + Ord, S: SimdElem<T>, const K: usize>(v: &mut [T]) {
+    let n = v.len();
+    let full = n / S::L;
+    let rem = n % S::L;
+    // Pad the partial block with the smallest value, which sorts to the back.
+    let mut tail = [S::MIN; MAX_L];
+    tail[..rem].copy_from_slice(&v[full * S::L..]);
+    let mut regs: [S::Simd; K] = core::array::from_fn(|r| match r.cmp(&full) {
+        Ordering::Less => unsafe { S::simd_from_slice(&v[r * S::L..]) },
+        Ordering::Equal => unsafe { S::simd_from_slice(&tail) },
+        Ordering::Greater => S::splat(S::MIN),
+    });
+    bitonic_sort_desc::<T, S, K>(&mut regs);
+    for (r, &reg) in regs[..full].iter().enumerate() {
+        unsafe { (v.as_mut_ptr().add(r * S::L) as *mut S::Simd).write_unaligned(reg) };
+    }
+    if rem > 0 {
+        unsafe { (tail.as_mut_ptr() as *mut S::Simd).write_unaligned(regs[full]) };
+        v[full * S::L..].copy_from_slice(&tail[..rem]);
+    }
+}
+
+/// Sort `K` registers decreasing, as one array of `K L` elements.
+///
+/// The stages are spelled out with constant `(k, j)`, so that after inlining all masks and
+/// shuffles are constants and the network is straight-line code.
+#[inline(always)]
+fn bitonic_sort_desc<T: Copy + Ord, S: SimdElem<T>, const K: usize>(regs: &mut [S::Simd; K]) {
+    let p = K * S::L;
+    macro_rules! stages {
+        ($($k:literal: $($j:literal)*;)*) => {
+            $(
+                if $k <= p {
+                    $(bitonic_stage::<T, S, K>(regs, $k, $j);)*
                 }
-            })
-            .unwrap_err()
+            )*
+        };
+    }
+    stages! {
+        2: 1;
+        4: 2 1;
+        8: 4 2 1;
+        16: 8 4 2 1;
+        32: 16 8 4 2 1;
+        64: 32 16 8 4 2 1;
+        128: 64 32 16 8 4 2 1;
     }
 }
 
-#[inline(never)]
-pub fn position_min<T: Copy + Ord, S: SimdElem<T>>(v: &mut Vec<T>) -> usize {
-    // Baseline:
-    // let mut min = S::MAX;
-    // let mut pos = 0;
-    // for i in 0..v.len() {
-    //     if v[i] <= min {
-    //         min = v[i];
-    //         pos = i;
-    //     }
-    // }
-    // return pos;
-
-    let mut min_pos = [0; 2];
-    let mut min_val = [S::MAX; 2];
-    for (i, &[l, r]) in v.as_chunks::<2>().0.iter().enumerate() {
-        if l < min_val[0] {
-            min_val[0] = l;
-            min_pos[0] = i * 2;
+/// Compare-exchange all pairs at distance `j`, in blocks of size `k` that alternate between
+/// decreasing and increasing.
+///
+/// Pairs at distance `j >= L` are in different registers. Pairs at distance `j < L` are within
+/// a register: compare it against its `j`-permuted copy, and blend the max into the lanes that
+/// should hold it.
+#[inline(always)]
+fn bitonic_stage<T: Copy + Ord, S: SimdElem<T>, const K: usize>(
+    regs: &mut [S::Simd; K],
+    k: usize,
+    j: usize,
+) {
+    let l = S::L;
+    let all = (1 << l) - 1;
+    if j >= l {
+        let jr = j / l;
+        for a in 0..K {
+            let b = a ^ jr;
+            if b > a {
+                let mn = S::simd_min(regs[a], regs[b]);
+                let mx = S::simd_max(regs[a], regs[b]);
+                if (a * l) & k == 0 {
+                    (regs[a], regs[b]) = (mx, mn);
+                } else {
+                    (regs[a], regs[b]) = (mn, mx);
+                }
+            }
         }
-        if r < min_val[1] {
-            min_val[1] = r;
-            min_pos[1] = i * 2 + 1;
+    } else {
+        for (a, reg) in regs.iter_mut().enumerate() {
+            let other = S::simd_permute_xor(*reg, j);
+            let mn = S::simd_min(*reg, other);
+            let mx = S::simd_max(*reg, other);
+            // The lower lane of each pair takes the max in decreasing blocks.
+            let lower = lanes_without_bit(j, l);
+            let decreasing = if k < l {
+                lanes_without_bit(k, l)
+            } else if (a * l) & k == 0 {
+                all
+            } else {
+                0
+            };
+            *reg = S::simd_select(!(lower ^ decreasing) & all, mx, mn);
         }
     }
-    if v.len() % 2 == 1 {
-        let l = *v.last().unwrap();
-        if l < min_val[0] {
-            min_val[0] = l;
-            min_pos[0] = v.len() - 1;
-        }
+}
+
+/// The lanes `x < l` for which `x & bit == 0`, as a bitmask.
+#[inline(always)]
+fn lanes_without_bit(bit: usize, l: usize) -> u64 {
+    // Repeat blocks of `bit` ones and `bit` zeros.
+    let block = (1u64 << bit) - 1;
+    let mut mask = block;
+    let mut width = 2 * bit;
+    while width < l {
+        mask |= mask << width;
+        width *= 2;
     }
-    if min_val[0] <= min_val[1] {
-        min_pos[0]
-    } else {
-        min_pos[1]
+    mask & ((1 << l) - 1)
+}
+
+/// Scalar fallback for [`SimdElem::position_min`] on fewer than `L` elements.
+fn position_min_scalar<T: Copy + Ord>(v: &[T]) -> usize {
+    let mut pos = 0;
+    for i in 1..v.len() {
+        if v[i] < v[pos] {
+            pos = i;
+        }
     }
+    pos
 }
 
 macro_rules! impl_simd_elem_32 {
     ($t:ty, $simd:ty) => {
         impl SimdElem<$t> for Avx2 {
             const L: usize = 8;
+            const MIN: $t = <$t>::MIN;
             const MAX: $t = <$t>::MAX;
             type Simd = $simd;
 
@@ -167,6 +896,76 @@ macro_rules! impl_simd_elem_32 {
                 a.simd_lt(b).to_bitmask() as u64
             }
 
+            #[inline(always)]
+            fn simd_eq_bitmask(a: $simd, b: $simd) -> u64 {
+                a.simd_eq(b).to_bitmask() as u64
+            }
+
+            #[inline(always)]
+            fn simd_min(a: $simd, b: $simd) -> $simd {
+                a.min(b)
+            }
+
+            #[inline(always)]
+            fn simd_max(a: $simd, b: $simd) -> $simd {
+                a.max(b)
+            }
+
+            #[inline(always)]
+            fn simd_select(mask: u64, t: $simd, f: $simd) -> $simd {
+                let bits = <$simd>::from(core::array::from_fn(|i| (1 << i) as $t));
+                let mask = (<$simd>::splat(mask as $t) & bits).simd_eq(bits);
+                mask.blend(t, f)
+            }
+
+            #[inline(always)]
+            fn simd_permute_xor(a: $simd, j: usize) -> $simd {
+                let a = a.to_array();
+                // Constant shuffles for each `j`.
+                <$simd>::from(match j {
+                    1 => core::array::from_fn(|i| a[i ^ 1]),
+                    2 => core::array::from_fn(|i| a[i ^ 2]),
+                    4 => core::array::from_fn(|i| a[i ^ 4]),
+                    8 => core::array::from_fn(|i| a[i ^ 8]),
+                    _ => unreachable!(),
+                })
+            }
+
+            #[inline(always)]
+            fn simd_lt_select(a: $simd, b: $simd, t: $simd, f: $simd) -> $simd {
+                a.simd_lt(b).blend(t, f)
+            }
+
+            #[inline(always)]
+            fn lane(a: $simd, i: usize) -> $t {
+                a.to_array()[i]
+            }
+
+            #[inline(always)]
+            fn reduce_min(a: $simd) -> $t {
+                // Fold the halves of the register onto each other.
+                let a = a.min(<Self as SimdElem<$t>>::simd_permute_xor(a, 4));
+                let a = a.min(<Self as SimdElem<$t>>::simd_permute_xor(a, 2));
+                let a = a.min(<Self as SimdElem<$t>>::simd_permute_xor(a, 1));
+                a.to_array()[0]
+            }
+
+            #[inline(always)]
+            fn simd_add(a: $simd, b: $simd) -> $simd {
+                a + b
+            }
+
+            #[inline(always)]
+            unsafe fn simd_gather(base: *const $t, idx: $simd) -> $simd {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+                    let idx: __m256i = transmute(idx);
+                    let vals: __m256i = _mm256_i32gather_epi32::<4>(base as *const i32, idx);
+                    transmute(vals)
+                }
+            }
+
             #[inline(always)]
             fn lane_indices() -> $simd {
                 <$simd>::from([0 as $t, 1, 2, 3, 4, 5, 6, 7])
@@ -177,11 +976,31 @@ macro_rules! impl_simd_elem_32 {
                 n as $t
             }
 
+            #[inline(always)]
+            fn to_usize(t: $t) -> usize {
+                t as usize
+            }
+
             #[inline(always)]
             fn wrapping_add_one(t: $t) -> $t {
                 t.wrapping_add(1)
             }
 
+            #[inline(always)]
+            fn midpoint(a: $t, b: $t) -> $t {
+                ((a as i128 + b as i128) >> 1) as $t
+            }
+
+            #[inline(always)]
+            fn to_radix(t: $t) -> u64 {
+                (t as i128 - <$t>::MIN as i128) as u64
+            }
+
+            #[inline(always)]
+            fn from_radix(r: u64) -> $t {
+                (r as i128 + <$t>::MIN as i128) as $t
+            }
+
             #[inline(always)]
             unsafe fn partition_fast<const EQUAL_DOWN: bool>(
                 vals: $simd,
@@ -193,7 +1012,7 @@ macro_rules! impl_simd_elem_32 {
             ) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
+                    use core::mem::transmute;
 
                     // bit i = lane i is small
                     let small: u8 = if EQUAL_DOWN {
@@ -234,7 +1053,7 @@ macro_rules! impl_simd_elem_32 {
             ) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
+                    use core::mem::transmute;
 
                     let mut small = vals.simd_lt(threshold).to_bitmask() as u8;
                     let mut large = !small;
@@ -262,6 +1081,87 @@ macro_rules! impl_simd_elem_32 {
                     *w_idx += small.count_ones() as usize;
                 }
             }
+
+            #[inline(always)]
+            unsafe fn partition_inplace<const EQUAL_DOWN: bool>(
+                vals: $simd,
+                threshold: $simd,
+                v: &mut [$t],
+                lo: &mut usize,
+                hi: &mut usize,
+            ) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    // bit i = lane i is small
+                    let small: u8 = if EQUAL_DOWN {
+                        !(threshold.simd_lt(vals).to_bitmask() as u8)
+                    } else {
+                        threshold.simd_gt(vals).to_bitmask() as u8
+                    };
+                    let large = !small;
+                    let vals: __m256i = transmute(vals);
+
+                    // Write large lanes to the front of the register, and store at `lo`.
+                    let key: __m256i = transmute(crate::simd::UNIQSHUF32[small as usize]);
+                    _mm256_storeu_si256(
+                        v.as_mut_ptr().add(*lo) as *mut __m256i,
+                        _mm256_permutevar8x32_epi32(vals, key),
+                    );
+                    *lo += large.count_ones() as usize;
+
+                    // Write small lanes to the back of the register, and store ending at `hi`.
+                    let key: __m256i = transmute(crate::simd::BACKSHUF32[small as usize]);
+                    _mm256_storeu_si256(
+                        v.as_mut_ptr().add(*hi - 8) as *mut __m256i,
+                        _mm256_permutevar8x32_epi32(vals, key),
+                    );
+                    *hi -= small.count_ones() as usize;
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn insert_store(vals: $simd, t: $t, k: usize, ptr: *mut $t) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let vals: __m256i = transmute(vals);
+                    let shifted = _mm256_permutevar8x32_epi32(
+                        vals,
+                        _mm256_setr_epi32(0, 0, 1, 2, 3, 4, 5, 6),
+                    );
+                    let lanes = _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7);
+                    let k = _mm256_set1_epi32(k as i32);
+                    // Keep the lanes before `k`, and put `t` in lane `k`.
+                    let out = _mm256_blendv_epi8(shifted, vals, _mm256_cmpgt_epi32(k, lanes));
+                    let out = _mm256_blendv_epi8(
+                        out,
+                        _mm256_set1_epi32(t as i32),
+                        _mm256_cmpeq_epi32(k, lanes),
+                    );
+                    _mm256_storeu_si256(ptr as *mut __m256i, out);
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn compress_store(mask: u64, vals: $simd, ptr: *mut $t) -> usize {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let mask = mask as u8;
+                    // UNIQSHUF32[k] drops the lanes set in k.
+                    let key: __m256i = transmute(crate::simd::UNIQSHUF32[!mask as usize]);
+                    let vals: __m256i = transmute(vals);
+                    _mm256_storeu_si256(
+                        ptr as *mut __m256i,
+                        _mm256_permutevar8x32_epi32(vals, key),
+                    );
+                    mask.count_ones() as usize
+                }
+            }
         }
     };
 }
@@ -270,6 +1170,7 @@ macro_rules! impl_simd_elem_64 {
     ($t:ty, $simd:ty) => {
         impl SimdElem<$t> for Avx2 {
             const L: usize = 4;
+            const MIN: $t = <$t>::MIN;
             const MAX: $t = <$t>::MAX;
             type Simd = $simd;
 
@@ -288,6 +1189,75 @@ macro_rules! impl_simd_elem_64 {
                 a.simd_lt(b).to_bitmask() as u64
             }
 
+            #[inline(always)]
+            fn simd_eq_bitmask(a: $simd, b: $simd) -> u64 {
+                a.simd_eq(b).to_bitmask() as u64
+            }
+
+            #[inline(always)]
+            fn simd_min(a: $simd, b: $simd) -> $simd {
+                a.min(b)
+            }
+
+            #[inline(always)]
+            fn simd_max(a: $simd, b: $simd) -> $simd {
+                a.max(b)
+            }
+
+            #[inline(always)]
+            fn simd_select(mask: u64, t: $simd, f: $simd) -> $simd {
+                let bits = <$simd>::from(core::array::from_fn(|i| (1 << i) as $t));
+                let mask = (<$simd>::splat(mask as $t) & bits).simd_eq(bits);
+                mask.blend(t, f)
+            }
+
+            #[inline(always)]
+            fn simd_permute_xor(a: $simd, j: usize) -> $simd {
+                let a = a.to_array();
+                // Constant shuffles for each `j`.
+                <$simd>::from(match j {
+                    1 => core::array::from_fn(|i| a[i ^ 1]),
+                    2 => core::array::from_fn(|i| a[i ^ 2]),
+                    4 => core::array::from_fn(|i| a[i ^ 4]),
+                    8 => core::array::from_fn(|i| a[i ^ 8]),
+                    _ => unreachable!(),
+                })
+            }
+
+            #[inline(always)]
+            fn simd_lt_select(a: $simd, b: $simd, t: $simd, f: $simd) -> $simd {
+                a.simd_lt(b).blend(t, f)
+            }
+
+            #[inline(always)]
+            fn lane(a: $simd, i: usize) -> $t {
+                a.to_array()[i]
+            }
+
+            #[inline(always)]
+            fn reduce_min(a: $simd) -> $t {
+                // Fold the halves of the register onto each other.
+                let a = a.min(<Self as SimdElem<$t>>::simd_permute_xor(a, 2));
+                let a = a.min(<Self as SimdElem<$t>>::simd_permute_xor(a, 1));
+                a.to_array()[0]
+            }
+
+            #[inline(always)]
+            fn simd_add(a: $simd, b: $simd) -> $simd {
+                a + b
+            }
+
+            #[inline(always)]
+            unsafe fn simd_gather(base: *const $t, idx: $simd) -> $simd {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+                    let idx: __m256i = transmute(idx);
+                    let vals: __m256i = _mm256_i64gather_epi64::<8>(base as *const i64, idx);
+                    transmute(vals)
+                }
+            }
+
             #[inline(always)]
             fn lane_indices() -> $simd {
                 <$simd>::from([0 as $t, 1, 2, 3])
@@ -298,11 +1268,31 @@ macro_rules! impl_simd_elem_64 {
                 n as $t
             }
 
+            #[inline(always)]
+            fn to_usize(t: $t) -> usize {
+                t as usize
+            }
+
             #[inline(always)]
             fn wrapping_add_one(t: $t) -> $t {
                 t.wrapping_add(1)
             }
 
+            #[inline(always)]
+            fn midpoint(a: $t, b: $t) -> $t {
+                ((a as i128 + b as i128) >> 1) as $t
+            }
+
+            #[inline(always)]
+            fn to_radix(t: $t) -> u64 {
+                (t as i128 - <$t>::MIN as i128) as u64
+            }
+
+            #[inline(always)]
+            fn from_radix(r: u64) -> $t {
+                (r as i128 + <$t>::MIN as i128) as $t
+            }
+
             #[inline(always)]
             unsafe fn partition_fast<const EQUAL_DOWN: bool>(
                 vals: $simd,
@@ -314,7 +1304,7 @@ macro_rules! impl_simd_elem_64 {
             ) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
+                    use core::mem::transmute;
 
                     // 4-bit mask: bit i = lane i is small.
                     let small: u8 = if EQUAL_DOWN {
@@ -334,56 +1324,137 @@ macro_rules! impl_simd_elem_64 {
                     );
                     *v_idx += large.count_ones() as usize;
 
-                    // To keep small lanes (keep_pattern = small): index = small ^ 0xF = large.
-                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[large as usize]);
+                    // To keep small lanes (keep_pattern = small): index = small ^ 0xF = large.
+                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[large as usize]);
+                    _mm256_storeu_si256(
+                        w.as_mut_ptr().add(*w_idx) as *mut __m256i,
+                        _mm256_permutevar8x32_epi32(vals, key),
+                    );
+                    *w_idx += small.count_ones() as usize;
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn partition_slow(
+                vals: $simd,
+                len: $simd,
+                threshold: $simd,
+                v: &mut [$t],
+                v_idx: &mut usize,
+                w: &mut [$t],
+                w_idx: &mut usize,
+            ) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let mut small = (vals.simd_lt(threshold).to_bitmask() as u8) & 0xF;
+                    let mut large = small ^ 0xF;
+                    let in_range = (len
+                        .simd_gt(<Self as SimdElem<$t>>::lane_indices())
+                        .to_bitmask() as u8)
+                        & 0xF;
+                    small &= in_range;
+                    large &= in_range;
+
+                    let vals: __m256i = transmute(vals);
+
+                    // To keep large lanes: index = large ^ 0xF.
+                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[(large ^ 0xF) as usize]);
+                    _mm256_storeu_si256(
+                        v.as_mut_ptr().add(*v_idx) as *mut __m256i,
+                        _mm256_permutevar8x32_epi32(vals, key),
+                    );
+                    *v_idx += large.count_ones() as usize;
+
+                    // To keep small lanes: index = small ^ 0xF.
+                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[(small ^ 0xF) as usize]);
+                    _mm256_storeu_si256(
+                        w.as_mut_ptr().add(*w_idx) as *mut __m256i,
+                        _mm256_permutevar8x32_epi32(vals, key),
+                    );
+                    *w_idx += small.count_ones() as usize;
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn partition_inplace<const EQUAL_DOWN: bool>(
+                vals: $simd,
+                threshold: $simd,
+                v: &mut [$t],
+                lo: &mut usize,
+                hi: &mut usize,
+            ) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    // 4-bit mask: bit i = lane i is small.
+                    let small: u8 = if EQUAL_DOWN {
+                        !(threshold.simd_lt(vals).to_bitmask() as u8) & 0xF
+                    } else {
+                        (threshold.simd_gt(vals).to_bitmask() as u8) & 0xF
+                    };
+                    let large = small ^ 0xF;
+                    let vals: __m256i = transmute(vals);
+
+                    // To keep large lanes at the front: index = large ^ 0xF = small.
+                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[small as usize]);
+                    _mm256_storeu_si256(
+                        v.as_mut_ptr().add(*lo) as *mut __m256i,
+                        _mm256_permutevar8x32_epi32(vals, key),
+                    );
+                    *lo += large.count_ones() as usize;
+
+                    // Move small lanes to the back, and store ending at `hi`.
+                    let key: __m256i = transmute(crate::simd::BACKSHUF64[small as usize]);
                     _mm256_storeu_si256(
-                        w.as_mut_ptr().add(*w_idx) as *mut __m256i,
+                        v.as_mut_ptr().add(*hi - 4) as *mut __m256i,
                         _mm256_permutevar8x32_epi32(vals, key),
                     );
-                    *w_idx += small.count_ones() as usize;
+                    *hi -= small.count_ones() as usize;
                 }
             }
 
             #[inline(always)]
-            unsafe fn partition_slow(
-                vals: $simd,
-                len: $simd,
-                threshold: $simd,
-                v: &mut [$t],
-                v_idx: &mut usize,
-                w: &mut [$t],
-                w_idx: &mut usize,
-            ) {
+            unsafe fn insert_store(vals: $simd, t: $t, k: usize, ptr: *mut $t) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
-
-                    let mut small = (vals.simd_lt(threshold).to_bitmask() as u8) & 0xF;
-                    let mut large = small ^ 0xF;
-                    let in_range = (len
-                        .simd_gt(<Self as SimdElem<$t>>::lane_indices())
-                        .to_bitmask() as u8)
-                        & 0xF;
-                    small &= in_range;
-                    large &= in_range;
+                    use core::mem::transmute;
 
                     let vals: __m256i = transmute(vals);
-
-                    // To keep large lanes: index = large ^ 0xF.
-                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[(large ^ 0xF) as usize]);
-                    _mm256_storeu_si256(
-                        v.as_mut_ptr().add(*v_idx) as *mut __m256i,
-                        _mm256_permutevar8x32_epi32(vals, key),
+                    let shifted = _mm256_permutevar8x32_epi32(
+                        vals,
+                        _mm256_setr_epi32(0, 1, 0, 1, 2, 3, 4, 5),
                     );
-                    *v_idx += large.count_ones() as usize;
+                    let lanes = _mm256_setr_epi64x(0, 1, 2, 3);
+                    let k = _mm256_set1_epi64x(k as i64);
+                    // Keep the lanes before `k`, and put `t` in lane `k`.
+                    let out = _mm256_blendv_epi8(shifted, vals, _mm256_cmpgt_epi64(k, lanes));
+                    let out = _mm256_blendv_epi8(
+                        out,
+                        _mm256_set1_epi64x(t as i64),
+                        _mm256_cmpeq_epi64(k, lanes),
+                    );
+                    _mm256_storeu_si256(ptr as *mut __m256i, out);
+                }
+            }
 
-                    // To keep small lanes: index = small ^ 0xF.
-                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[(small ^ 0xF) as usize]);
+            #[inline(always)]
+            unsafe fn compress_store(mask: u64, vals: $simd, ptr: *mut $t) -> usize {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let mask = mask as u8 & 0xF;
+                    // UNIQSHUF64[k] keeps the lanes set in k ^ 0xF.
+                    let key: __m256i = transmute(crate::simd::UNIQSHUF64[(mask ^ 0xF) as usize]);
+                    let vals: __m256i = transmute(vals);
                     _mm256_storeu_si256(
-                        w.as_mut_ptr().add(*w_idx) as *mut __m256i,
+                        ptr as *mut __m256i,
                         _mm256_permutevar8x32_epi32(vals, key),
                     );
-                    *w_idx += small.count_ones() as usize;
+                    mask.count_ones() as usize
                 }
             }
         }
@@ -391,9 +1462,10 @@ macro_rules! impl_simd_elem_64 {
 }
 
 macro_rules! impl_simd_elem_32_avx512 {
-    ($t:ty, $simd:ty) => {
+    ($t:ty, $simd:ty, $reduce:ident) => {
         impl<const CS: bool> SimdElem<$t> for Avx512<CS> {
             const L: usize = 16;
+            const MIN: $t = <$t>::MIN;
             const MAX: $t = <$t>::MAX;
             type Simd = $simd;
 
@@ -412,6 +1484,76 @@ macro_rules! impl_simd_elem_32_avx512 {
                 a.simd_lt(b).to_bitmask() as u64
             }
 
+            #[inline(always)]
+            fn simd_eq_bitmask(a: $simd, b: $simd) -> u64 {
+                a.simd_eq(b).to_bitmask() as u64
+            }
+
+            #[inline(always)]
+            fn simd_min(a: $simd, b: $simd) -> $simd {
+                a.min(b)
+            }
+
+            #[inline(always)]
+            fn simd_max(a: $simd, b: $simd) -> $simd {
+                a.max(b)
+            }
+
+            #[inline(always)]
+            fn simd_select(mask: u64, t: $simd, f: $simd) -> $simd {
+                let bits = <$simd>::from(core::array::from_fn(|i| (1 << i) as $t));
+                let mask = (<$simd>::splat(mask as $t) & bits).simd_eq(bits);
+                mask.blend(t, f)
+            }
+
+            #[inline(always)]
+            fn simd_permute_xor(a: $simd, j: usize) -> $simd {
+                let a = a.to_array();
+                // Constant shuffles for each `j`.
+                <$simd>::from(match j {
+                    1 => core::array::from_fn(|i| a[i ^ 1]),
+                    2 => core::array::from_fn(|i| a[i ^ 2]),
+                    4 => core::array::from_fn(|i| a[i ^ 4]),
+                    8 => core::array::from_fn(|i| a[i ^ 8]),
+                    _ => unreachable!(),
+                })
+            }
+
+            #[inline(always)]
+            fn simd_lt_select(a: $simd, b: $simd, t: $simd, f: $simd) -> $simd {
+                a.simd_lt(b).blend(t, f)
+            }
+
+            #[inline(always)]
+            fn lane(a: $simd, i: usize) -> $t {
+                a.to_array()[i]
+            }
+
+            #[inline(always)]
+            fn reduce_min(a: $simd) -> $t {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    let a: __m512i = core::mem::transmute(a);
+                    $reduce(a) as $t
+                }
+            }
+
+            #[inline(always)]
+            fn simd_add(a: $simd, b: $simd) -> $simd {
+                a + b
+            }
+
+            #[inline(always)]
+            unsafe fn simd_gather(base: *const $t, idx: $simd) -> $simd {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+                    let idx: __m512i = transmute(idx);
+                    let vals: __m512i = _mm512_i32gather_epi32::<4>(idx, base as *const _);
+                    transmute(vals)
+                }
+            }
+
             #[inline(always)]
             fn lane_indices() -> $simd {
                 <$simd>::from([0 as $t, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])
@@ -422,11 +1564,31 @@ macro_rules! impl_simd_elem_32_avx512 {
                 n as $t
             }
 
+            #[inline(always)]
+            fn to_usize(t: $t) -> usize {
+                t as usize
+            }
+
             #[inline(always)]
             fn wrapping_add_one(t: $t) -> $t {
                 t.wrapping_add(1)
             }
 
+            #[inline(always)]
+            fn midpoint(a: $t, b: $t) -> $t {
+                ((a as i128 + b as i128) >> 1) as $t
+            }
+
+            #[inline(always)]
+            fn to_radix(t: $t) -> u64 {
+                (t as i128 - <$t>::MIN as i128) as u64
+            }
+
+            #[inline(always)]
+            fn from_radix(r: u64) -> $t {
+                (r as i128 + <$t>::MIN as i128) as $t
+            }
+
             #[inline(always)]
             unsafe fn partition_fast<const EQUAL_DOWN: bool>(
                 vals: $simd,
@@ -438,7 +1600,7 @@ macro_rules! impl_simd_elem_32_avx512 {
             ) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
+                    use core::mem::transmute;
 
                     let small: u16 = if EQUAL_DOWN {
                         !(threshold.simd_lt(vals).to_bitmask() as u16)
@@ -486,7 +1648,7 @@ macro_rules! impl_simd_elem_32_avx512 {
             ) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
+                    use core::mem::transmute;
 
                     let in_range: u16 = len
                         .simd_gt(<Self as SimdElem<$t>>::lane_indices())
@@ -520,14 +1682,101 @@ macro_rules! impl_simd_elem_32_avx512 {
                     }
                 }
             }
+
+            #[inline(always)]
+            unsafe fn partition_inplace<const EQUAL_DOWN: bool>(
+                vals: $simd,
+                threshold: $simd,
+                v: &mut [$t],
+                lo: &mut usize,
+                hi: &mut usize,
+            ) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let small: u16 = if EQUAL_DOWN {
+                        !(threshold.simd_lt(vals).to_bitmask() as u16)
+                    } else {
+                        threshold.simd_gt(vals).to_bitmask() as u16
+                    };
+                    let large: u16 = !small;
+                    let vals: __m512i = transmute(vals);
+                    let small_len = small.count_ones() as usize;
+
+                    if CS {
+                        let cv = _mm512_maskz_compress_epi32(large, vals);
+                        _mm512_storeu_si512(v.as_mut_ptr().add(*lo) as *mut __m512i, cv);
+                        *lo += large.count_ones() as usize;
+
+                        // Compress the small lanes, and expand them into the top lanes.
+                        let top = ((1 << 16) - (1 << (16 - small_len))) as u16;
+                        let cw = _mm512_maskz_compress_epi32(small, vals);
+                        let cw = _mm512_maskz_expand_epi32(top, cw);
+                        _mm512_storeu_si512(v.as_mut_ptr().add(*hi - 16) as *mut __m512i, cw);
+                        *hi -= small_len;
+                    } else {
+                        _mm512_mask_compressstoreu_epi32(
+                            v.as_mut_ptr().add(*lo) as *mut i32,
+                            large,
+                            vals,
+                        );
+                        *lo += large.count_ones() as usize;
+
+                        *hi -= small_len;
+                        _mm512_mask_compressstoreu_epi32(
+                            v.as_mut_ptr().add(*hi) as *mut i32,
+                            small,
+                            vals,
+                        );
+                    }
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn insert_store(vals: $simd, t: $t, k: usize, ptr: *mut $t) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let vals: __m512i = transmute(vals);
+                    let shifted = _mm512_permutexvar_epi32(
+                        _mm512_setr_epi32(0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14),
+                        vals,
+                    );
+                    // Keep the lanes before `k`, and put `t` in lane `k`.
+                    let out = _mm512_mask_mov_epi32(shifted, ((1 << k) - 1) as u16, vals);
+                    let out = _mm512_mask_set1_epi32(out, (1 << k) as u16, t as i32);
+                    _mm512_storeu_si512(ptr as *mut __m512i, out);
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn compress_store(mask: u64, vals: $simd, ptr: *mut $t) -> usize {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let mask = mask as u16;
+                    let vals: __m512i = transmute(vals);
+                    if CS {
+                        let c = _mm512_maskz_compress_epi32(mask, vals);
+                        _mm512_storeu_si512(ptr as *mut __m512i, c);
+                    } else {
+                        _mm512_mask_compressstoreu_epi32(ptr as *mut i32, mask, vals);
+                    }
+                    mask.count_ones() as usize
+                }
+            }
         }
     };
 }
 
 macro_rules! impl_simd_elem_64_avx512 {
-    ($t:ty, $simd:ty) => {
+    ($t:ty, $simd:ty, $reduce:ident) => {
         impl<const CS: bool> SimdElem<$t> for Avx512<CS> {
             const L: usize = 8;
+            const MIN: $t = <$t>::MIN;
             const MAX: $t = <$t>::MAX;
             type Simd = $simd;
 
@@ -546,6 +1795,76 @@ macro_rules! impl_simd_elem_64_avx512 {
                 a.simd_lt(b).to_bitmask() as u64
             }
 
+            #[inline(always)]
+            fn simd_eq_bitmask(a: $simd, b: $simd) -> u64 {
+                a.simd_eq(b).to_bitmask() as u64
+            }
+
+            #[inline(always)]
+            fn simd_min(a: $simd, b: $simd) -> $simd {
+                a.min(b)
+            }
+
+            #[inline(always)]
+            fn simd_max(a: $simd, b: $simd) -> $simd {
+                a.max(b)
+            }
+
+            #[inline(always)]
+            fn simd_select(mask: u64, t: $simd, f: $simd) -> $simd {
+                let bits = <$simd>::from(core::array::from_fn(|i| (1 << i) as $t));
+                let mask = (<$simd>::splat(mask as $t) & bits).simd_eq(bits);
+                mask.blend(t, f)
+            }
+
+            #[inline(always)]
+            fn simd_permute_xor(a: $simd, j: usize) -> $simd {
+                let a = a.to_array();
+                // Constant shuffles for each `j`.
+                <$simd>::from(match j {
+                    1 => core::array::from_fn(|i| a[i ^ 1]),
+                    2 => core::array::from_fn(|i| a[i ^ 2]),
+                    4 => core::array::from_fn(|i| a[i ^ 4]),
+                    8 => core::array::from_fn(|i| a[i ^ 8]),
+                    _ => unreachable!(),
+                })
+            }
+
+            #[inline(always)]
+            fn simd_lt_select(a: $simd, b: $simd, t: $simd, f: $simd) -> $simd {
+                a.simd_lt(b).blend(t, f)
+            }
+
+            #[inline(always)]
+            fn lane(a: $simd, i: usize) -> $t {
+                a.to_array()[i]
+            }
+
+            #[inline(always)]
+            fn reduce_min(a: $simd) -> $t {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    let a: __m512i = core::mem::transmute(a);
+                    $reduce(a) as $t
+                }
+            }
+
+            #[inline(always)]
+            fn simd_add(a: $simd, b: $simd) -> $simd {
+                a + b
+            }
+
+            #[inline(always)]
+            unsafe fn simd_gather(base: *const $t, idx: $simd) -> $simd {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+                    let idx: __m512i = transmute(idx);
+                    let vals: __m512i = _mm512_i64gather_epi64::<8>(idx, base as *const _);
+                    transmute(vals)
+                }
+            }
+
             #[inline(always)]
             fn lane_indices() -> $simd {
                 <$simd>::from([0 as $t, 1, 2, 3, 4, 5, 6, 7])
@@ -556,11 +1875,31 @@ macro_rules! impl_simd_elem_64_avx512 {
                 n as $t
             }
 
+            #[inline(always)]
+            fn to_usize(t: $t) -> usize {
+                t as usize
+            }
+
             #[inline(always)]
             fn wrapping_add_one(t: $t) -> $t {
                 t.wrapping_add(1)
             }
 
+            #[inline(always)]
+            fn midpoint(a: $t, b: $t) -> $t {
+                ((a as i128 + b as i128) >> 1) as $t
+            }
+
+            #[inline(always)]
+            fn to_radix(t: $t) -> u64 {
+                (t as i128 - <$t>::MIN as i128) as u64
+            }
+
+            #[inline(always)]
+            fn from_radix(r: u64) -> $t {
+                (r as i128 + <$t>::MIN as i128) as $t
+            }
+
             #[inline(always)]
             unsafe fn partition_fast<const EQUAL_DOWN: bool>(
                 vals: $simd,
@@ -572,7 +1911,7 @@ macro_rules! impl_simd_elem_64_avx512 {
             ) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
+                    use core::mem::transmute;
 
                     let small: u8 = if EQUAL_DOWN {
                         !(threshold.simd_lt(vals).to_bitmask() as u8)
@@ -620,7 +1959,7 @@ macro_rules! impl_simd_elem_64_avx512 {
             ) {
                 unsafe {
                     use core::arch::x86_64::*;
-                    use std::mem::transmute;
+                    use core::mem::transmute;
 
                     let in_range: u8 = len
                         .simd_gt(<Self as SimdElem<$t>>::lane_indices())
@@ -654,6 +1993,90 @@ macro_rules! impl_simd_elem_64_avx512 {
                     }
                 }
             }
+
+            #[inline(always)]
+            unsafe fn partition_inplace<const EQUAL_DOWN: bool>(
+                vals: $simd,
+                threshold: $simd,
+                v: &mut [$t],
+                lo: &mut usize,
+                hi: &mut usize,
+            ) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let small: u8 = if EQUAL_DOWN {
+                        !(threshold.simd_lt(vals).to_bitmask() as u8)
+                    } else {
+                        threshold.simd_gt(vals).to_bitmask() as u8
+                    };
+                    let large: u8 = !small;
+                    let vals: __m512i = transmute(vals);
+                    let small_len = small.count_ones() as usize;
+
+                    if CS {
+                        let cv = _mm512_maskz_compress_epi64(large, vals);
+                        _mm512_storeu_si512(v.as_mut_ptr().add(*lo) as *mut __m512i, cv);
+                        *lo += large.count_ones() as usize;
+
+                        // Compress the small lanes, and expand them into the top lanes.
+                        let top = ((1 << 8) - (1 << (8 - small_len))) as u8;
+                        let cw = _mm512_maskz_compress_epi64(small, vals);
+                        let cw = _mm512_maskz_expand_epi64(top, cw);
+                        _mm512_storeu_si512(v.as_mut_ptr().add(*hi - 8) as *mut __m512i, cw);
+                        *hi -= small_len;
+                    } else {
+                        _mm512_mask_compressstoreu_epi64(
+                            v.as_mut_ptr().add(*lo) as *mut i64,
+                            large,
+                            vals,
+                        );
+                        *lo += large.count_ones() as usize;
+
+                        *hi -= small_len;
+                        _mm512_mask_compressstoreu_epi64(
+                            v.as_mut_ptr().add(*hi) as *mut i64,
+                            small,
+                            vals,
+                        );
+                    }
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn insert_store(vals: $simd, t: $t, k: usize, ptr: *mut $t) {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let vals: __m512i = transmute(vals);
+                    let shifted =
+                        _mm512_permutexvar_epi64(_mm512_setr_epi64(0, 0, 1, 2, 3, 4, 5, 6), vals);
+                    // Keep the lanes before `k`, and put `t` in lane `k`.
+                    let out = _mm512_mask_mov_epi64(shifted, ((1 << k) - 1) as u8, vals);
+                    let out = _mm512_mask_set1_epi64(out, (1 << k) as u8, t as i64);
+                    _mm512_storeu_si512(ptr as *mut __m512i, out);
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn compress_store(mask: u64, vals: $simd, ptr: *mut $t) -> usize {
+                unsafe {
+                    use core::arch::x86_64::*;
+                    use core::mem::transmute;
+
+                    let mask = mask as u8;
+                    let vals: __m512i = transmute(vals);
+                    if CS {
+                        let c = _mm512_maskz_compress_epi64(mask, vals);
+                        _mm512_storeu_si512(ptr as *mut __m512i, c);
+                    } else {
+                        _mm512_mask_compressstoreu_epi64(ptr as *mut i64, mask, vals);
+                    }
+                    mask.count_ones() as usize
+                }
+            }
         }
     };
 }
@@ -663,10 +2086,10 @@ impl_simd_elem_32!(u32, wide::u32x8);
 impl_simd_elem_64!(i64, wide::i64x4);
 impl_simd_elem_64!(u64, wide::u64x4);
 
-impl_simd_elem_32_avx512!(i32, wide::i32x16);
-impl_simd_elem_32_avx512!(u32, wide::u32x16);
-impl_simd_elem_64_avx512!(i64, wide::i64x8);
-impl_simd_elem_64_avx512!(u64, wide::u64x8);
+impl_simd_elem_32_avx512!(i32, wide::i32x16, _mm512_reduce_min_epi32);
+impl_simd_elem_32_avx512!(u32, wide::u32x16, _mm512_reduce_min_epu32);
+impl_simd_elem_64_avx512!(i64, wide::i64x8, _mm512_reduce_min_epi64);
+impl_simd_elem_64_avx512!(u64, wide::u64x8, _mm512_reduce_min_epu64);
 
 /// For each of 256 masks of which elements are different than their predecessor,
 /// a shuffle that sends those new elements to the beginning.
@@ -952,3 +2375,43 @@ transmute([
 0, 0, 0, 0, 0, 0, 0, 0, //1111
 ])
 };
+
+/// For each of 256 masks of lanes to keep, a shuffle that sends those lanes to the end,
+/// keeping their order.
+pub(crate) const BACKSHUF32: [[i32; 8]; 256] = {
+    let mut table = [[0; 8]; 256];
+    let mut mask = 0;
+    while mask < 256 {
+        let mut j = 8 - (mask as u32).count_ones() as usize;
+        let mut i: usize = 0;
+        while i < 8 {
+            if mask >> i & 1 == 1 {
+                table[mask][j] = i as i32;
+                j += 1;
+            }
+            i += 1;
+        }
+        mask += 1;
+    }
+    table
+};
+
+/// Like [`BACKSHUF32`], for 32-bit shuffle instructions on 64-bit data.
+pub(crate) const BACKSHUF64: [[i32; 8]; 16] = {
+    let mut table = [[0; 8]; 16];
+    let mut mask = 0;
+    while mask < 16 {
+        let mut j = 4 - (mask as u32).count_ones() as usize;
+        let mut i: usize = 0;
+        while i < 4 {
+            if mask >> i & 1 == 1 {
+                table[mask][2 * j] = 2 * i as i32;
+                table[mask][2 * j + 1] = 2 * i as i32 + 1;
+                j += 1;
+            }
+            i += 1;
+        }
+        mask += 1;
+    }
+    table
+};
@@ -0,0 +1,45 @@
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+use crate::{Elem, rng::Rng};
+
+/// Splits a large layer into several layers at once.
+pub trait SplitterStrategy {
+    /// The number of layers to split a layer of `n` elements into.
+    /// Values below 3 use the binary partition of the `PivotStrategy`.
+    fn ways(n: usize) -> usize;
+    /// Append at most `k - 1` strictly decreasing splitters taken from `layer`.
+    fn pick<T: Elem>(layer: &[T], k: usize, splitters: &mut Vec<T>, rng: &mut Rng);
+}
+
+/// Always split a layer in two around a single pivot.
+pub struct BinarySplit;
+impl SplitterStrategy for BinarySplit {
+    fn ways(_: usize) -> usize {
+        2
+    }
+    fn pick<T: Elem>(_: &[T], _: usize, _: &mut Vec<T>, _: &mut Rng) {}
+}
+
+/// Split layers of at least `MIN_LEN` elements `K` ways, as in super-scalar sample sort.
+///
+/// The splitters are equidistant elements of a sorted random sample of `K * OVERSAMPLING` elements.
+pub struct SampleSplit<const K: usize, const OVERSAMPLING: usize = 4, const MIN_LEN: usize = 4096>;
+impl<const K: usize, const OVERSAMPLING: usize, const MIN_LEN: usize> SplitterStrategy
+    for SampleSplit<K, OVERSAMPLING, MIN_LEN>
+{
+    fn ways(n: usize) -> usize {
+        if n >= MIN_LEN { K } else { 2 }
+    }
+
+    fn pick<T: Elem>(layer: &[T], k: usize, splitters: &mut Vec<T>, rng: &mut Rng) {
+        let n = layer.len();
+        let mut sample: Vec<T> = (0..k * OVERSAMPLING)
+            .map(|_| layer[rng.random_range(0..n)])
+            .collect();
+        sample.sort_unstable_by_key(|&x| Reverse(x));
+
+        splitters.extend((1..k).map(|i| sample[i * OVERSAMPLING]));
+        splitters.dedup();
+    }
+}
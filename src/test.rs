@@ -1,8 +1,19 @@
-use std::cmp::Reverse;
+use std::{alloc::Layout, boxed::Box, cell::Cell, cmp::Reverse, rc::Rc, vec::Vec};
 
+use crate::bucket_alloc::{AllocError, Bucket, BucketAlloc, Global, HugePages};
+use crate::simd::{PivotTree, partition_multiway, push_batch, push_position};
 use crate::{
-    ConfigurableSimdQuickHeap, SimdElem, pivot_strategies::MedianOfM,
-    rebalancing_strategies::PivotForgetting,
+    Backend, CompactSimdQuickHeap, ConfigurableSimdQuickHeap, DYNAMIC_N, Elem, QuickHeapBuilder,
+    SimdElem,
+    pivot_strategies::{
+        AdaptivePivot, DynPivot, MedianOfM, PivotStrategy, RadixPivot, RangeMidpointPivot,
+        SketchPivot,
+    },
+    rebalancing_strategies::{
+        CostModelRebalancing, DynRebalancing, NoRebalancing, PivotForgetting, PushSplitting,
+        RebalancingStrategy,
+    },
+    splitter_strategies::{BinarySplit, SampleSplit, SplitterStrategy},
 };
 
 /// Element-type capabilities needed by the generators.
@@ -32,6 +43,179 @@ impl GenElem for i64 {
     fn wrapping_dec(self) -> Self { self.wrapping_sub(1) }
 }
 
+#[rustfmt::skip]
+impl GenElem for u32 {
+    fn gen_random() -> Self { rand::random() }
+    fn gen_min() -> Self { u32::MIN }
+    fn gen_max() -> Self { u32::MAX }
+    fn wrapping_inc(self) -> Self { self.wrapping_add(1) }
+    fn wrapping_dec(self) -> Self { self.wrapping_sub(1) }
+}
+
+#[rustfmt::skip]
+impl GenElem for i32 {
+    fn gen_random() -> Self { rand::random() }
+    fn gen_min() -> Self { i32::MIN }
+    fn gen_max() -> Self { i32::MAX }
+    fn wrapping_inc(self) -> Self { self.wrapping_add(1) }
+    fn wrapping_dec(self) -> Self { self.wrapping_sub(1) }
+}
+
+/// The heap variants under test.
+trait TestHeap<T: Copy> {
+    fn new() -> Self;
+    fn push(&mut self, t: T);
+    fn push_batch(&mut self, ts: &[T]) {
+        for &t in ts {
+            self.push(t);
+        }
+    }
+    fn pop(&mut self) -> Option<T>;
+}
+
+impl<
+    T: Elem,
+    S: SimdElem<T>,
+    P: PivotStrategy,
+    R: RebalancingStrategy<T> + Default,
+    const N: usize,
+    const SORT: bool,
+    Sp: SplitterStrategy,
+    A: BucketAlloc + Default,
+> TestHeap<T> for ConfigurableSimdQuickHeap<T, S, P, R, N, SORT, Sp, A>
+{
+    fn new() -> Self {
+        Self::default()
+    }
+    fn push(&mut self, t: T) {
+        self.push(t)
+    }
+    fn push_batch(&mut self, ts: &[T]) {
+        self.push_batch(ts)
+    }
+    fn pop(&mut self) -> Option<T> {
+        self.pop()
+    }
+}
+
+type Heap<T, S> = ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, PivotForgetting<2, 128>>;
+
+/// A heap with a larger sorted bottom layer.
+type WideHeap<T, S> = ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, PivotForgetting<2, 128>, 64>;
+
+/// A heap with an unsorted bottom layer.
+type UnsortedHeap<T, S> =
+    ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, PivotForgetting<2, 128>, 32, false>;
+
+/// A heap that splits layers of at least 64 elements 16 ways.
+type SampleHeap<T, S> = ConfigurableSimdQuickHeap<
+    T,
+    S,
+    MedianOfM<3>,
+    PivotForgetting<2, 128>,
+    32,
+    true,
+    SampleSplit<16, 4, 64>,
+>;
+
+/// A heap with aligned buckets, and huge pages for buckets of at least 64 KiB.
+type HugePageHeap<T, S> = ConfigurableSimdQuickHeap<
+    T,
+    S,
+    MedianOfM<3>,
+    PivotForgetting<2, 128>,
+    32,
+    true,
+    BinarySplit,
+    HugePages<{ 1 << 16 }>,
+>;
+
+/// Always pick the smallest element, so that each split only peels off the minimum.
+#[derive(Default)]
+struct MinPivot;
+impl PivotStrategy for MinPivot {
+    fn pick<T: Elem, S: SimdElem<T>>(
+        &mut self,
+        layer: &[T],
+        _: &mut crate::rng::Rng,
+    ) -> (T, usize) {
+        let pos = (0..layer.len()).min_by_key(|&i| layer[i]).unwrap();
+        (layer[pos], pos)
+    }
+}
+
+/// A heap with adversarial pivots, that relies on the fallback after bad splits.
+type AdversarialHeap<T, S> = ConfigurableSimdQuickHeap<T, S, MinPivot>;
+
+/// A heap that learns its sample sizes online.
+type AdaptiveHeap<T, S> = ConfigurableSimdQuickHeap<T, S, AdaptivePivot>;
+
+/// A heap that splits layers at the midpoint of their range.
+type MidpointHeap<T, S> = ConfigurableSimdQuickHeap<T, S, RangeMidpointPivot>;
+
+/// A heap that splits layers at radix boundaries.
+type RadixHeap<T, S> = ConfigurableSimdQuickHeap<T, S, RadixPivot>;
+
+/// A heap that takes its pivots from a quantile sketch of the pushed keys.
+type SketchHeap<T, S> = ConfigurableSimdQuickHeap<T, S, SketchPivot>;
+
+/// A heap with a small bottom layer size that is set at runtime.
+struct DynamicHeap<T: Elem, S: SimdElem<T>>(
+    ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, NoRebalancing, DYNAMIC_N>,
+);
+impl<T: Elem, S: SimdElem<T>> TestHeap<T> for DynamicHeap<T, S> {
+    fn new() -> Self {
+        let mut q = ConfigurableSimdQuickHeap::default();
+        q.set_bottom_threshold(5);
+        Self(q)
+    }
+    fn push(&mut self, t: T) {
+        self.0.push(t)
+    }
+    fn push_batch(&mut self, ts: &[T]) {
+        self.0.push_batch(ts)
+    }
+    fn pop(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+}
+
+/// A heap that splits layers over a small budget during pushes.
+type PushSplitHeap<T, S> = ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, PushSplitting<64, 4>>;
+
+/// A heap that merges layers eagerly by its cost model, and checks often.
+type CostModelHeap<T, S> =
+    ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, CostModelRebalancing<1, 8>>;
+
+/// A heap that partitions all layers with prefetching.
+struct StreamingHeap<T: Elem, S: SimdElem<T>>(Heap<T, S>);
+impl<T: Elem, S: SimdElem<T>> TestHeap<T> for StreamingHeap<T, S> {
+    fn new() -> Self {
+        let mut h = Heap::default();
+        h.set_stream_threshold(0);
+        Self(h)
+    }
+    fn push(&mut self, t: T) {
+        self.0.push(t)
+    }
+    fn pop(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+}
+
+type CompactHeap<T, S> = CompactSimdQuickHeap<T, S, MedianOfM<3>>;
+impl<T: Elem, S: SimdElem<T>> TestHeap<T> for CompactHeap<T, S> {
+    fn new() -> Self {
+        Self::default()
+    }
+    fn push(&mut self, t: T) {
+        self.push(t)
+    }
+    fn pop(&mut self) -> Option<T> {
+        self.pop()
+    }
+}
+
 trait Generator<T> {
     fn new() -> Self;
     fn popped(&mut self, x: T);
@@ -110,16 +294,15 @@ impl<T: GenElem> Generator<T> for MostlyMinGen {
     }
 }
 
-fn heapsort_with_gen<T, S, G>()
+fn heapsort_with_gen<T, H, G>()
 where
     T: GenElem,
-    S: SimdElem<T>,
+    H: TestHeap<T>,
     G: Generator<T>,
 {
     let g = &mut G::new();
     for n in [10, 100, 1000, 10000, 100000] {
-        let mut q =
-            <ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, PivotForgetting<2, 128>>>::default();
+        let mut q = H::new();
         for _ in 0..n {
             q.push(g.get());
         }
@@ -135,16 +318,50 @@ where
     }
 }
 
-fn wiggle_with_gen<T, S, G>()
+fn batch_with_gen<T, H, G>()
 where
     T: GenElem,
-    S: SimdElem<T>,
+    H: TestHeap<T>,
     G: Generator<T>,
 {
     let g = &mut G::new();
     for n in [10, 100, 1000, 10000, 100000] {
-        let mut q1 =
-            <ConfigurableSimdQuickHeap<T, S, MedianOfM<3>, PivotForgetting<2, 128>>>::default();
+        let mut q1 = H::new();
+        let mut q2 = std::collections::binary_heap::BinaryHeap::default();
+
+        // Push batches of varying size, and pop a few elements in between.
+        let mut pushed = 0;
+        while pushed < n {
+            let batch: Vec<T> = (0..rand::random_range(0..100)).map(|_| g.get()).collect();
+            q1.push_batch(&batch);
+            q2.extend(batch.iter().map(|&x| Reverse(x)));
+            pushed += batch.len();
+
+            for _ in 0..rand::random_range(0..50) {
+                let p = q1.pop();
+                assert_eq!(p, q2.pop().map(|v| v.0));
+                if let Some(v) = p {
+                    g.popped(v);
+                }
+            }
+        }
+        while let Some(p) = q1.pop() {
+            assert_eq!(Some(p), q2.pop().map(|v| v.0));
+            g.popped(p);
+        }
+        assert_eq!(q2.pop(), None);
+    }
+}
+
+fn wiggle_with_gen<T, H, G>()
+where
+    T: GenElem,
+    H: TestHeap<T>,
+    G: Generator<T>,
+{
+    let g = &mut G::new();
+    for n in [10, 100, 1000, 10000, 100000] {
+        let mut q1 = H::new();
         let mut q2 = std::collections::binary_heap::BinaryHeap::default();
 
         // (push pop push) xn
@@ -187,31 +404,1004 @@ where
 
 #[rustfmt::skip]
 macro_rules! all_tests {
-    ($elem:ty, $simd:ty) => {
-        #[test] fn heapsort_random()      { heapsort_with_gen::<$elem, $simd, RandomGen>(); }
-        #[test] fn heapsort_increasing()  { heapsort_with_gen::<$elem, $simd, IncreasingGen<$elem>>(); }
-        #[test] fn heapsort_decreasing()  { heapsort_with_gen::<$elem, $simd, DecreasingGen<$elem>>(); }
-        #[test] fn heapsort_mostly_max()  { heapsort_with_gen::<$elem, $simd, MostlyMaxGen>(); }
-        #[test] fn heapsort_mostly_min()  { heapsort_with_gen::<$elem, $simd, MostlyMinGen>(); }
-
-        #[test] fn wiggle_random()        { wiggle_with_gen::<$elem, $simd, RandomGen>(); }
-        #[test] fn wiggle_increasing()    { wiggle_with_gen::<$elem, $simd, IncreasingGen<$elem>>(); }
-        #[test] fn wiggle_decreasing()    { wiggle_with_gen::<$elem, $simd, DecreasingGen<$elem>>(); }
-        #[test] fn wiggle_mostly_max()    { wiggle_with_gen::<$elem, $simd, MostlyMaxGen>(); }
-        #[test] fn wiggle_mostly_min()    { wiggle_with_gen::<$elem, $simd, MostlyMinGen>(); }
+    ($elem:ty, $heap:ty) => {
+        #[test] fn heapsort_random()      { heapsort_with_gen::<$elem, $heap, RandomGen>(); }
+        #[test] fn heapsort_increasing()  { heapsort_with_gen::<$elem, $heap, IncreasingGen<$elem>>(); }
+        #[test] fn heapsort_decreasing()  { heapsort_with_gen::<$elem, $heap, DecreasingGen<$elem>>(); }
+        #[test] fn heapsort_mostly_max()  { heapsort_with_gen::<$elem, $heap, MostlyMaxGen>(); }
+        #[test] fn heapsort_mostly_min()  { heapsort_with_gen::<$elem, $heap, MostlyMinGen>(); }
+
+        #[test] fn batch_random()         { batch_with_gen::<$elem, $heap, RandomGen>(); }
+        #[test] fn batch_increasing()     { batch_with_gen::<$elem, $heap, IncreasingGen<$elem>>(); }
+        #[test] fn batch_decreasing()     { batch_with_gen::<$elem, $heap, DecreasingGen<$elem>>(); }
+
+        #[test] fn wiggle_random()        { wiggle_with_gen::<$elem, $heap, RandomGen>(); }
+        #[test] fn wiggle_increasing()    { wiggle_with_gen::<$elem, $heap, IncreasingGen<$elem>>(); }
+        #[test] fn wiggle_decreasing()    { wiggle_with_gen::<$elem, $heap, DecreasingGen<$elem>>(); }
+        #[test] fn wiggle_mostly_max()    { wiggle_with_gen::<$elem, $heap, MostlyMaxGen>(); }
+        #[test] fn wiggle_mostly_min()    { wiggle_with_gen::<$elem, $heap, MostlyMinGen>(); }
+    };
+}
+
+/// Define a test that runs `$f::<T, S>()` for every element type and SIMD backend.
+macro_rules! elem_matrix_test {
+    ($name:ident, $f:ident) => {
+        #[test]
+        fn $name() {
+            $f::<u64, crate::Avx2>();
+            $f::<i64, crate::Avx2>();
+            $f::<u32, crate::Avx2>();
+            $f::<i32, crate::Avx2>();
+            #[cfg(target_feature = "avx512f")]
+            {
+                $f::<u64, crate::Avx512>();
+                $f::<i64, crate::Avx512>();
+                $f::<u32, crate::Avx512>();
+                $f::<i32, crate::Avx512>();
+            }
+        }
     };
 }
 
 #[rustfmt::skip]
 mod u64 {
-    mod avx2   { use super::super::*; all_tests!(u64, crate::Avx2); }
+    mod avx2   { use super::super::*; all_tests!(u64, Heap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod avx512 { use super::super::*; all_tests!(u64, Heap<u64, crate::Avx512>); }
+
+    mod wide_avx2   { use super::super::*; all_tests!(u64, WideHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod wide_avx512 { use super::super::*; all_tests!(u64, WideHeap<u64, crate::Avx512>); }
+
+    mod unsorted_avx2   { use super::super::*; all_tests!(u64, UnsortedHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod unsorted_avx512 { use super::super::*; all_tests!(u64, UnsortedHeap<u64, crate::Avx512>); }
+
+    mod sample_avx2   { use super::super::*; all_tests!(u64, SampleHeap<u64, crate::Avx2>); }
     #[cfg(target_feature = "avx512f")]
-    mod avx512 { use super::super::*; all_tests!(u64, crate::Avx512); }
+    mod sample_avx512 { use super::super::*; all_tests!(u64, SampleHeap<u64, crate::Avx512>); }
+
+    mod huge_avx2   { use super::super::*; all_tests!(u64, HugePageHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod huge_avx512 { use super::super::*; all_tests!(u64, HugePageHeap<u64, crate::Avx512>); }
+
+    mod streaming_avx2   { use super::super::*; all_tests!(u64, StreamingHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod streaming_avx512 { use super::super::*; all_tests!(u64, StreamingHeap<u64, crate::Avx512>); }
+
+    mod compact_avx2   { use super::super::*; all_tests!(u64, CompactHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod compact_avx512 { use super::super::*; all_tests!(u64, CompactHeap<u64, crate::Avx512>); }
+
+    mod adversarial_avx2   { use super::super::*; all_tests!(u64, AdversarialHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adversarial_avx512 { use super::super::*; all_tests!(u64, AdversarialHeap<u64, crate::Avx512>); }
+
+    mod adaptive_avx2   { use super::super::*; all_tests!(u64, AdaptiveHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adaptive_avx512 { use super::super::*; all_tests!(u64, AdaptiveHeap<u64, crate::Avx512>); }
+
+    mod midpoint_avx2   { use super::super::*; all_tests!(u64, MidpointHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod midpoint_avx512 { use super::super::*; all_tests!(u64, MidpointHeap<u64, crate::Avx512>); }
+
+    mod radix_avx2   { use super::super::*; all_tests!(u64, RadixHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod radix_avx512 { use super::super::*; all_tests!(u64, RadixHeap<u64, crate::Avx512>); }
+
+    mod sketch_avx2   { use super::super::*; all_tests!(u64, SketchHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod sketch_avx512 { use super::super::*; all_tests!(u64, SketchHeap<u64, crate::Avx512>); }
+
+    mod dynamic_avx2   { use super::super::*; all_tests!(u64, DynamicHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod dynamic_avx512 { use super::super::*; all_tests!(u64, DynamicHeap<u64, crate::Avx512>); }
+
+    mod push_split_avx2   { use super::super::*; all_tests!(u64, PushSplitHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod push_split_avx512 { use super::super::*; all_tests!(u64, PushSplitHeap<u64, crate::Avx512>); }
+
+    mod cost_model_avx2   { use super::super::*; all_tests!(u64, CostModelHeap<u64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod cost_model_avx512 { use super::super::*; all_tests!(u64, CostModelHeap<u64, crate::Avx512>); }
 }
 
 #[rustfmt::skip]
 mod i64 {
-    mod avx2   { use super::super::*; all_tests!(i64, crate::Avx2); }
+    mod avx2   { use super::super::*; all_tests!(i64, Heap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod avx512 { use super::super::*; all_tests!(i64, Heap<i64, crate::Avx512>); }
+
+    mod wide_avx2   { use super::super::*; all_tests!(i64, WideHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod wide_avx512 { use super::super::*; all_tests!(i64, WideHeap<i64, crate::Avx512>); }
+
+    mod unsorted_avx2   { use super::super::*; all_tests!(i64, UnsortedHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod unsorted_avx512 { use super::super::*; all_tests!(i64, UnsortedHeap<i64, crate::Avx512>); }
+
+    mod sample_avx2   { use super::super::*; all_tests!(i64, SampleHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod sample_avx512 { use super::super::*; all_tests!(i64, SampleHeap<i64, crate::Avx512>); }
+
+    mod huge_avx2   { use super::super::*; all_tests!(i64, HugePageHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod huge_avx512 { use super::super::*; all_tests!(i64, HugePageHeap<i64, crate::Avx512>); }
+
+    mod streaming_avx2   { use super::super::*; all_tests!(i64, StreamingHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod streaming_avx512 { use super::super::*; all_tests!(i64, StreamingHeap<i64, crate::Avx512>); }
+
+    mod compact_avx2   { use super::super::*; all_tests!(i64, CompactHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod compact_avx512 { use super::super::*; all_tests!(i64, CompactHeap<i64, crate::Avx512>); }
+
+    mod adversarial_avx2   { use super::super::*; all_tests!(i64, AdversarialHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adversarial_avx512 { use super::super::*; all_tests!(i64, AdversarialHeap<i64, crate::Avx512>); }
+
+    mod adaptive_avx2   { use super::super::*; all_tests!(i64, AdaptiveHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adaptive_avx512 { use super::super::*; all_tests!(i64, AdaptiveHeap<i64, crate::Avx512>); }
+
+    mod midpoint_avx2   { use super::super::*; all_tests!(i64, MidpointHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod midpoint_avx512 { use super::super::*; all_tests!(i64, MidpointHeap<i64, crate::Avx512>); }
+
+    mod radix_avx2   { use super::super::*; all_tests!(i64, RadixHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod radix_avx512 { use super::super::*; all_tests!(i64, RadixHeap<i64, crate::Avx512>); }
+
+    mod sketch_avx2   { use super::super::*; all_tests!(i64, SketchHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod sketch_avx512 { use super::super::*; all_tests!(i64, SketchHeap<i64, crate::Avx512>); }
+
+    mod dynamic_avx2   { use super::super::*; all_tests!(i64, DynamicHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod dynamic_avx512 { use super::super::*; all_tests!(i64, DynamicHeap<i64, crate::Avx512>); }
+
+    mod push_split_avx2   { use super::super::*; all_tests!(i64, PushSplitHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod push_split_avx512 { use super::super::*; all_tests!(i64, PushSplitHeap<i64, crate::Avx512>); }
+
+    mod cost_model_avx2   { use super::super::*; all_tests!(i64, CostModelHeap<i64, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod cost_model_avx512 { use super::super::*; all_tests!(i64, CostModelHeap<i64, crate::Avx512>); }
+}
+
+#[rustfmt::skip]
+mod u32 {
+    mod avx2   { use super::super::*; all_tests!(u32, Heap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod avx512 { use super::super::*; all_tests!(u32, Heap<u32, crate::Avx512>); }
+
+    mod wide_avx2   { use super::super::*; all_tests!(u32, WideHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod wide_avx512 { use super::super::*; all_tests!(u32, WideHeap<u32, crate::Avx512>); }
+
+    mod unsorted_avx2   { use super::super::*; all_tests!(u32, UnsortedHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod unsorted_avx512 { use super::super::*; all_tests!(u32, UnsortedHeap<u32, crate::Avx512>); }
+
+    mod sample_avx2   { use super::super::*; all_tests!(u32, SampleHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod sample_avx512 { use super::super::*; all_tests!(u32, SampleHeap<u32, crate::Avx512>); }
+
+    mod huge_avx2   { use super::super::*; all_tests!(u32, HugePageHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod huge_avx512 { use super::super::*; all_tests!(u32, HugePageHeap<u32, crate::Avx512>); }
+
+    mod streaming_avx2   { use super::super::*; all_tests!(u32, StreamingHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod streaming_avx512 { use super::super::*; all_tests!(u32, StreamingHeap<u32, crate::Avx512>); }
+
+    mod compact_avx2   { use super::super::*; all_tests!(u32, CompactHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod compact_avx512 { use super::super::*; all_tests!(u32, CompactHeap<u32, crate::Avx512>); }
+
+    mod adversarial_avx2   { use super::super::*; all_tests!(u32, AdversarialHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adversarial_avx512 { use super::super::*; all_tests!(u32, AdversarialHeap<u32, crate::Avx512>); }
+
+    mod adaptive_avx2   { use super::super::*; all_tests!(u32, AdaptiveHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adaptive_avx512 { use super::super::*; all_tests!(u32, AdaptiveHeap<u32, crate::Avx512>); }
+
+    mod midpoint_avx2   { use super::super::*; all_tests!(u32, MidpointHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod midpoint_avx512 { use super::super::*; all_tests!(u32, MidpointHeap<u32, crate::Avx512>); }
+
+    mod radix_avx2   { use super::super::*; all_tests!(u32, RadixHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod radix_avx512 { use super::super::*; all_tests!(u32, RadixHeap<u32, crate::Avx512>); }
+
+    mod sketch_avx2   { use super::super::*; all_tests!(u32, SketchHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod sketch_avx512 { use super::super::*; all_tests!(u32, SketchHeap<u32, crate::Avx512>); }
+
+    mod dynamic_avx2   { use super::super::*; all_tests!(u32, DynamicHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod dynamic_avx512 { use super::super::*; all_tests!(u32, DynamicHeap<u32, crate::Avx512>); }
+
+    mod push_split_avx2   { use super::super::*; all_tests!(u32, PushSplitHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod push_split_avx512 { use super::super::*; all_tests!(u32, PushSplitHeap<u32, crate::Avx512>); }
+
+    mod cost_model_avx2   { use super::super::*; all_tests!(u32, CostModelHeap<u32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod cost_model_avx512 { use super::super::*; all_tests!(u32, CostModelHeap<u32, crate::Avx512>); }
+}
+
+#[rustfmt::skip]
+mod i32 {
+    mod avx2   { use super::super::*; all_tests!(i32, Heap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod avx512 { use super::super::*; all_tests!(i32, Heap<i32, crate::Avx512>); }
+
+    mod wide_avx2   { use super::super::*; all_tests!(i32, WideHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod wide_avx512 { use super::super::*; all_tests!(i32, WideHeap<i32, crate::Avx512>); }
+
+    mod unsorted_avx2   { use super::super::*; all_tests!(i32, UnsortedHeap<i32, crate::Avx2>); }
     #[cfg(target_feature = "avx512f")]
-    mod avx512 { use super::super::*; all_tests!(i64, crate::Avx512); }
+    mod unsorted_avx512 { use super::super::*; all_tests!(i32, UnsortedHeap<i32, crate::Avx512>); }
+
+    mod sample_avx2   { use super::super::*; all_tests!(i32, SampleHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod sample_avx512 { use super::super::*; all_tests!(i32, SampleHeap<i32, crate::Avx512>); }
+
+    mod huge_avx2   { use super::super::*; all_tests!(i32, HugePageHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod huge_avx512 { use super::super::*; all_tests!(i32, HugePageHeap<i32, crate::Avx512>); }
+
+    mod streaming_avx2   { use super::super::*; all_tests!(i32, StreamingHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod streaming_avx512 { use super::super::*; all_tests!(i32, StreamingHeap<i32, crate::Avx512>); }
+
+    mod compact_avx2   { use super::super::*; all_tests!(i32, CompactHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod compact_avx512 { use super::super::*; all_tests!(i32, CompactHeap<i32, crate::Avx512>); }
+
+    mod adversarial_avx2   { use super::super::*; all_tests!(i32, AdversarialHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adversarial_avx512 { use super::super::*; all_tests!(i32, AdversarialHeap<i32, crate::Avx512>); }
+
+    mod adaptive_avx2   { use super::super::*; all_tests!(i32, AdaptiveHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod adaptive_avx512 { use super::super::*; all_tests!(i32, AdaptiveHeap<i32, crate::Avx512>); }
+
+    mod midpoint_avx2   { use super::super::*; all_tests!(i32, MidpointHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod midpoint_avx512 { use super::super::*; all_tests!(i32, MidpointHeap<i32, crate::Avx512>); }
+
+    mod radix_avx2   { use super::super::*; all_tests!(i32, RadixHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod radix_avx512 { use super::super::*; all_tests!(i32, RadixHeap<i32, crate::Avx512>); }
+
+    mod sketch_avx2   { use super::super::*; all_tests!(i32, SketchHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod sketch_avx512 { use super::super::*; all_tests!(i32, SketchHeap<i32, crate::Avx512>); }
+
+    mod dynamic_avx2   { use super::super::*; all_tests!(i32, DynamicHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod dynamic_avx512 { use super::super::*; all_tests!(i32, DynamicHeap<i32, crate::Avx512>); }
+
+    mod push_split_avx2   { use super::super::*; all_tests!(i32, PushSplitHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod push_split_avx512 { use super::super::*; all_tests!(i32, PushSplitHeap<i32, crate::Avx512>); }
+
+    mod cost_model_avx2   { use super::super::*; all_tests!(i32, CostModelHeap<i32, crate::Avx2>); }
+    #[cfg(target_feature = "avx512f")]
+    mod cost_model_avx512 { use super::super::*; all_tests!(i32, CostModelHeap<i32, crate::Avx512>); }
+}
+
+fn sort_desc_with<T: GenElem, S: SimdElem<T>>() {
+    for n in 0..=8 * S::L + 1 {
+        for _ in 0..10 {
+            let mut v: Vec<T> = (0..n).map(|_| T::gen_random()).collect();
+            if n % 2 == 0 {
+                // Also test duplicates and the extreme values.
+                v.iter_mut().step_by(3).for_each(|x| *x = T::gen_min());
+                v.iter_mut().step_by(5).for_each(|x| *x = T::gen_max());
+            }
+            let mut expected = v.clone();
+            expected.sort_unstable_by_key(|&x| Reverse(x));
+            S::sort_desc(&mut v);
+            assert_eq!(v, expected);
+        }
+    }
+}
+
+elem_matrix_test!(sort_desc, sort_desc_with);
+
+fn push_position_with<T: GenElem, S: SimdElem<T>>() {
+    for n in [0, 1, 15, 16, 17, 100, 255, 256, 257, 1000, 5000] {
+        let mut values: Vec<T> = (0..n).map(|_| T::gen_random()).collect();
+        if n % 2 == 0 {
+            // Also test duplicates and the extreme values.
+            values.iter_mut().step_by(3).for_each(|x| *x = T::gen_min());
+            values.iter_mut().step_by(7).for_each(|x| *x = T::gen_max());
+        }
+        values.sort_unstable_by_key(|&x| Reverse(x));
+
+        // Grow the pivots one by one like the heap does, with slack for SIMD reads.
+        let mut pivots = Bucket::<T>::new();
+        pivots.reserve(n + 64);
+        let mut tree = PivotTree::default();
+        for &p in &values {
+            pivots.push(p);
+            tree.sync(&pivots);
+        }
+
+        let check = |pivots: &Bucket<T>, tree: &PivotTree<T>| {
+            let queries = (0..100)
+                .map(|_| T::gen_random())
+                .chain(values.iter().copied())
+                .chain([T::gen_min(), T::gen_max()]);
+            for t in queries {
+                let expected = pivots.iter().filter(|&&p| t < p).count();
+                assert_eq!(push_position::<T, S, _>(pivots, tree, t), expected);
+            }
+        };
+        check(&pivots, &tree);
+
+        // Batches are classified the same way.
+        let ts: Vec<T> = (0..1000)
+            .map(|_| T::gen_random())
+            .chain(values.iter().copied())
+            .collect();
+        let mut buckets: Vec<Bucket<T>> = (0..=n).map(|_| Bucket::new()).collect();
+        push_batch::<T, S, _>(&pivots, &tree, &mut buckets, &ts);
+        let mut expected = vec![vec![]; n + 1];
+        for &t in &ts {
+            expected[pivots.iter().filter(|&&p| t < p).count()].push(t);
+        }
+        for (mut b, mut e) in buckets.into_iter().zip(expected) {
+            b.sort_unstable();
+            e.sort_unstable();
+            assert_eq!(*b, *e);
+        }
+
+        // Shrink and rebuild.
+        pivots.truncate(n / 3);
+        tree.sync(&pivots);
+        check(&pivots, &tree);
+        tree.rebuild(&pivots);
+        check(&pivots, &tree);
+    }
+}
+
+elem_matrix_test!(push_position_tree, push_position_with);
+
+fn partition_multiway_with<T: GenElem + Elem, S: SimdElem<T>>() {
+    // Also tree sizes that are not a power of two, and duplicates of the splitters.
+    for k in [3, 4, 5, 16, 17, 100, 256] {
+        let mut splitters: Vec<T> = (0..k - 1).map(|_| T::gen_random()).collect();
+        splitters.sort_unstable_by(|a, b| b.cmp(a));
+        splitters.dedup();
+        let ts: Vec<T> = (0..1003)
+            .map(|_| T::gen_random())
+            .chain(splitters.iter().copied())
+            .chain([T::gen_min(), T::gen_max()])
+            .collect();
+        let mut v = Bucket::<T>::new();
+        v.extend_from_slice(&ts);
+        let mut out: Vec<Bucket<T>> = (1..k).map(|_| Bucket::new()).collect();
+        partition_multiway::<T, S, _>(&mut v, &splitters, &mut out);
+
+        let mut expected = vec![vec![]; splitters.len() + 1];
+        for &t in &ts {
+            expected[splitters.iter().filter(|&&s| t < s).count()].push(t);
+        }
+        for (mut b, mut e) in [v].into_iter().chain(out).zip(expected) {
+            b.sort_unstable();
+            e.sort_unstable();
+            assert_eq!(*b, *e);
+        }
+    }
+}
+
+elem_matrix_test!(partition_multiway_tree, partition_multiway_with);
+
+/// An arena-like backend that fails once its byte budget is used up.
+#[derive(Clone)]
+struct Budget(Rc<Cell<usize>>);
+impl BucketAlloc for Budget {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let left = self.0.get();
+        if layout.size() > left {
+            return std::ptr::null_mut();
+        }
+        self.0.set(left - layout.size());
+        unsafe { Global.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.set(self.0.get() + layout.size());
+        unsafe { Global.dealloc(ptr, layout) }
+    }
+}
+
+fn try_push_with<T: GenElem + Elem, S: SimdElem<T>>() {
+    let budget = Budget(Rc::new(Cell::new(1 << 16)));
+    let mut heap = ConfigurableSimdQuickHeap::<
+        T,
+        S,
+        MedianOfM<3>,
+        NoRebalancing,
+        32,
+        true,
+        BinarySplit,
+        Budget,
+    >::new_in(budget.clone());
+
+    // Push until the budget runs out.
+    let mut pushed = vec![];
+    loop {
+        let t = T::gen_random();
+        if heap.try_push(t).is_err() {
+            break;
+        }
+        pushed.push(t);
+    }
+    assert!(!pushed.is_empty());
+    assert_eq!(heap.len(), pushed.len());
+    assert_eq!(heap.try_reserve(1 << 20), Err(AllocError));
+
+    // After raising the budget, the heap is intact.
+    let outstanding = (1 << 16) - budget.0.get();
+    budget.0.set(1 << 30);
+    heap.try_reserve(100).unwrap();
+    pushed.sort_unstable();
+    for &t in &pushed {
+        assert_eq!(heap.pop(), Some(t));
+    }
+    assert_eq!(heap.pop(), None);
+
+    // All memory is returned.
+    drop(heap);
+    assert_eq!(budget.0.get(), (1 << 30) + outstanding);
+}
+
+elem_matrix_test!(try_push_budget, try_push_with);
+
+/// `try_push` does not rebalance, since that may allocate, but the next `push` does.
+#[test]
+fn try_push_no_rebalancing() {
+    let mut q = PushSplitHeap::<u64, crate::Avx2>::default();
+    for t in 0..1000u64 {
+        q.try_push(t).unwrap();
+    }
+    assert!(q.pivots.is_empty());
+    q.push(1000);
+    assert_eq!(q.pivots.len(), 1);
+    for t in 0..=1000u64 {
+        assert_eq!(q.pop(), Some(t));
+    }
+}
+
+/// Counts the live bytes, and the most that were live at once.
+#[derive(Clone, Default)]
+struct Peak(Rc<(Cell<usize>, Cell<usize>)>);
+impl BucketAlloc for Peak {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let live = self.0.0.get() + layout.size();
+        self.0.0.set(live);
+        self.0.1.set(self.0.1.get().max(live));
+        unsafe { Global.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.0.set(self.0.0.get() - layout.size());
+        unsafe { Global.dealloc(ptr, layout) }
+    }
+}
+
+/// Splitting a layer hands off its small elements without copying them.
+#[test]
+fn split_in_place() {
+    let peak = Peak::default();
+    let mut q = ConfigurableSimdQuickHeap::<
+        u64,
+        crate::Avx2,
+        MedianOfM<3>,
+        NoRebalancing,
+        32,
+        true,
+        BinarySplit,
+        Peak,
+    >::new_in(peak.clone());
+    let n = 1 << 16;
+    let mut ts: Vec<u64> = (0..n).map(|_| rand::random()).collect();
+    for &t in &ts {
+        q.push(t);
+    }
+    assert!(q.pivots.is_empty());
+
+    // The first pop splits the layer of `n` elements down to the bottom one.
+    let live = peak.0.0.get();
+    peak.0.1.set(live);
+    ts.sort_unstable();
+    assert_eq!(q.pop(), Some(ts[0]));
+    assert!(q.pivots.len() > 5);
+    assert!(peak.0.1.get() - live < n * size_of::<u64>() / 64);
+
+    for &t in &ts[1..] {
+        assert_eq!(q.pop(), Some(t));
+    }
+    assert_eq!(q.pop(), None);
+    drop(q);
+    assert_eq!(peak.0.0.get(), 0);
+}
+
+/// Heaps with the same seed go through the same layouts.
+fn seed_with<T: GenElem + Elem, S: SimdElem<T>>() {
+    let ts: Vec<T> = (0..10000).map(|_| T::gen_random()).collect();
+    let run = |seed| {
+        let mut q = SampleHeap::<T, S>::with_seed(seed);
+        let mut c = CompactHeap::<T, S>::with_seed(seed);
+        let mut caps = vec![];
+        for (i, &t) in ts.iter().enumerate() {
+            q.push(t);
+            c.push(t);
+            if i % 3 == 0 {
+                assert_eq!(q.pop(), c.pop());
+                caps.push((q.capacity(), c.capacity()));
+            }
+        }
+        caps
+    };
+    assert_eq!(run(1), run(1));
+    assert_eq!(run(42), run(42));
+}
+
+elem_matrix_test!(seed_reproducible, seed_with);
+
+/// A layer of equal elements is split off, and then popped without further partitioning.
+fn all_equal_with<T: GenElem + Elem, S: SimdElem<T>>() {
+    let x = T::gen_random().max(T::gen_min().wrapping_inc());
+    let mut q = Heap::<T, S>::default();
+    let mut b = std::collections::BinaryHeap::new();
+    for _ in 0..10000 {
+        q.push(x);
+        b.push(Reverse(x));
+    }
+    assert_eq!(q.pop(), b.pop().map(|v| v.0));
+    let layers = q.pivots.len();
+    assert!(q.all_equal[layers]);
+    for i in 0..9000 {
+        assert_eq!(q.pop(), b.pop().map(|v| v.0));
+        // Smaller elements unmark the layer again.
+        if i < 5000 {
+            assert!(q.pivots.len() <= layers);
+        } else if i == 5000 {
+            q.push(x.wrapping_dec());
+            b.push(Reverse(x.wrapping_dec()));
+            assert!(!q.all_equal[q.pivots.len()]);
+        }
+    }
+    while let Some(t) = q.pop() {
+        assert_eq!(Some(t), b.pop().map(|v| v.0));
+    }
+    assert_eq!(b.pop(), None);
+}
+
+elem_matrix_test!(all_equal, all_equal_with);
+
+/// The compact heap, which does not mark layers of equal elements, still pops many
+/// duplicates in order.
+fn compact_duplicates_with<T: GenElem + Elem, S: SimdElem<T>>() {
+    let x = T::gen_random();
+    let mut q = CompactHeap::<T, S>::default();
+    let mut b = std::collections::BinaryHeap::new();
+    for i in 0..10000 {
+        let t = if i % 100 == 0 { T::gen_random() } else { x };
+        q.push(t);
+        b.push(Reverse(t));
+        if i % 3 == 0 {
+            assert_eq!(q.pop(), b.pop().map(|v| v.0));
+        }
+    }
+    while let Some(t) = q.pop() {
+        assert_eq!(Some(t), b.pop().map(|v| v.0));
+    }
+    assert_eq!(b.pop(), None);
+}
+
+elem_matrix_test!(compact_duplicates, compact_duplicates_with);
+
+/// The adaptive pivot strategy samples more for large layers than for small ones.
+#[test]
+fn adaptive_sample_size() {
+    let mut p = AdaptivePivot::<64>::default();
+    let mut rng = crate::rng::Rng::default();
+    for n in [64, 1 << 20] {
+        let layer: Vec<u32> = (0..n as u32).collect();
+        for _ in 0..1000 {
+            let (pivot, _) = p.pick::<u32, crate::Avx2>(&layer, &mut rng);
+            let small = pivot as usize + 1;
+            p.observe(small.min(n - small) as f64 / n as f64, n);
+        }
+    }
+    assert_eq!(p.sample_size(64), 1);
+    assert!(p.sample_size(1 << 20) > 15);
+}
+
+/// The midpoint strategy splits at the middle of the range, and samples after a skewed split.
+#[test]
+fn range_midpoint_fallback() {
+    let mut p = RangeMidpointPivot::<3>::default();
+    let mut rng = crate::rng::Rng::default();
+    let uniform: Vec<i64> = (-500..500).rev().collect();
+    assert_eq!(p.pick::<i64, crate::Avx2>(&uniform, &mut rng), (-1, 999));
+    p.observe(0.5, 1000);
+
+    let skewed: Vec<i64> = (0..1000).chain([1 << 40]).collect();
+    assert_eq!(
+        p.pick::<i64, crate::Avx2>(&skewed, &mut rng),
+        (1 << 39, 1000)
+    );
+    p.observe(1.0 / 1001.0, 1001);
+    let (pivot, pos) = p.pick::<i64, crate::Avx2>(&skewed, &mut rng);
+    assert_eq!(skewed[pos], pivot);
+}
+
+/// Radix pivots split off the bucket of the minimum relative to the last popped key.
+#[test]
+fn radix_pivot_buckets() {
+    let mut p = RadixPivot::default();
+    let mut rng = crate::rng::Rng::default();
+    let mut pick = |p: &mut RadixPivot, layer: &[u32]| p.pick::<u32, crate::Avx2>(layer, &mut rng);
+
+    // The bucket of 5 relative to 0 is [4, 8).
+    assert_eq!(pick(&mut p, &[12, 5, 7, 6]), (7, 3));
+    // Bucket 0 only holds the last popped key.
+    p.on_pop::<u32, crate::Avx2>(5);
+    assert_eq!(pick(&mut p, &[12, 5, 7, 6]), (5, 3));
+    // The bucket of 6 relative to 5 is [6, 8).
+    assert_eq!(pick(&mut p, &[12, 6, 7]), (7, 2));
+    // Within one bucket, split at the highest differing bit.
+    p.on_pop::<u32, crate::Avx2>(0);
+    assert_eq!(pick(&mut p, &[9, 13, 12]), (11, 2));
+
+    // Signed keys keep their order.
+    let mut p = RadixPivot::default();
+    p.on_pop::<i32, crate::Avx2>(-1);
+    let (pivot, _) = p.pick::<i32, crate::Avx2>(&[-1, 0, 3], &mut rng);
+    assert_eq!(pivot, -1);
+}
+
+/// The sketch proposes the median of the pushed keys in the range of the layer.
+#[test]
+fn sketch_pivot_median() {
+    let mut p = SketchPivot::<64, 8, 3>::default();
+    let mut rng = crate::rng::Rng::default();
+    let mut keys: Vec<i64> = (-10000..10000).collect();
+    for i in (1..keys.len()).rev() {
+        keys.swap(i, rng.random_range(0..i + 1));
+    }
+    for &k in &keys {
+        p.on_push::<i64, crate::Avx2>(k);
+    }
+
+    // The range of the layer comes from the last pop and the pivot above.
+    let layer: Vec<i64> = (-1000..3000).step_by(7).collect();
+    p.on_pop::<i64, crate::Avx2>(-1000);
+    p.on_split::<i64, crate::Avx2>(Some(3000));
+    let (pivot, pos) = p.pick::<i64, crate::Avx2>(&layer, &mut rng);
+    assert_eq!(pos, layer.len() - 1);
+    assert!((800..1200).contains(&pivot), "{pivot}");
+}
+
+/// Pushes go straight into hinted layers, and wrong hints are skipped when popping.
+fn pivot_hints_with<T: GenElem + Elem, S: SimdElem<T>>() {
+    let check = |q: &mut Heap<T, S>, ts: &[T]| {
+        let mut sorted = ts.to_vec();
+        sorted.sort();
+        for &t in ts {
+            q.push(t);
+        }
+        assert_eq!(q.len(), ts.len());
+        for &t in &sorted {
+            assert_eq!(q.pop(), Some(t));
+        }
+        assert_eq!(q.pop(), None);
+    };
+    let ts: Vec<T> = (0..5000).map(|_| T::gen_random()).collect();
+
+    // Unsorted and duplicate hints, and hints beyond the pushed keys.
+    let mut hints: Vec<T> = ts[..20].to_vec();
+    hints.extend_from_slice(&ts[..5]);
+    hints.extend([T::gen_min(), T::gen_max()]);
+    let mut q = Heap::<T, S>::with_pivot_hints(&hints);
+    assert!(q.pivots.len() >= 20 && q.pivots.len() <= 22);
+    for &t in &ts {
+        q.push(t);
+    }
+    assert!(q.buckets[0].len() < ts.len());
+    q.clear_keep_pivots();
+    assert!(q.is_empty());
+    check(&mut q, &ts);
+
+    // Refill with a subset of the keys, leaving many layers empty.
+    let mut q = Heap::<T, S>::default();
+    for &t in &ts {
+        q.push(t);
+    }
+    for _ in 0..100 {
+        q.pop();
+    }
+    let layers = q.pivots.len();
+    q.clear_keep_pivots();
+    assert_eq!(q.pivots.len(), layers);
+    check(&mut q, &ts[..30]);
+
+    q.clear();
+    assert!(q.pivots.is_empty());
+    check(&mut q, &ts);
+}
+
+elem_matrix_test!(pivot_hints, pivot_hints_with);
+
+/// Growing the bottom layer size at runtime keeps the bottom layer sorted.
+#[test]
+fn bottom_threshold() {
+    let mut q =
+        ConfigurableSimdQuickHeap::<u64, crate::Avx2, MedianOfM<3>, NoRebalancing, DYNAMIC_N>::default();
+    let mut b = std::collections::BinaryHeap::new();
+    for i in 0..3000u64 {
+        let t = i.wrapping_mul(0x9e3779b97f4a7c15) >> 20;
+        q.push(t);
+        b.push(Reverse(t));
+        if i % 500 == 499 {
+            q.set_bottom_threshold([1, 64, 3, 200, 16, 7][i as usize / 500]);
+        }
+        if i % 3 == 0 {
+            assert_eq!(q.pop(), b.pop().map(|v| v.0));
+        }
+    }
+    while let Some(Reverse(t)) = b.pop() {
+        assert_eq!(q.pop(), Some(t));
+    }
+}
+
+#[test]
+#[should_panic]
+fn bottom_threshold_fixed() {
+    Heap::<u64, crate::Avx2>::default().set_bottom_threshold(8);
+}
+
+/// Every configuration of the builder returns the elements in order.
+#[test]
+fn builder_configs() {
+    let ts: Vec<u32> = (0..3000).map(|_| u32::gen_random() % 1000).collect();
+    let mut sorted = ts.clone();
+    sorted.sort();
+    let pivots = [
+        DynPivot::MedianOf(5),
+        DynPivot::MedianOfMedians,
+        DynPivot::Adaptive(Default::default()),
+        DynPivot::RangeMidpoint(Default::default()),
+        DynPivot::Radix(Default::default()),
+        DynPivot::Sketch(Default::default()),
+    ];
+    for backend in [Backend::Avx2, Backend::Avx512] {
+        for rebalancing in [
+            DynRebalancing::None,
+            DynRebalancing::NaiveLog(Default::default()),
+            DynRebalancing::PivotForgetting(Default::default()),
+            DynRebalancing::PushSplitting(Default::default()),
+            DynRebalancing::CostModel(Default::default()),
+        ] {
+            for sorted_layer in [true, false] {
+                for (i, pivot) in pivots.iter().enumerate() {
+                    let mut q = QuickHeapBuilder::new()
+                        .backend(backend)
+                        .rebalancing(rebalancing.clone())
+                        .sorted(sorted_layer)
+                        .pivot_strategy(pivot.clone())
+                        .bottom_threshold(1 + 10 * i)
+                        .capacity(ts.len())
+                        .seed(i as u64)
+                        .build::<u32>();
+                    assert!(q.capacity() >= ts.len());
+                    let expected = match backend {
+                        Backend::Avx512 if !cfg!(target_feature = "avx512f") => Backend::Avx2,
+                        backend => backend,
+                    };
+                    assert_eq!(q.backend(), expected);
+                    q.push_batch(&ts[..1000]);
+                    for &t in &ts[1000..] {
+                        q.push(t);
+                    }
+                    assert_eq!(q.len(), ts.len());
+                    for &t in &sorted {
+                        assert_eq!(q.pop(), Some(t));
+                    }
+                    assert!(q.is_empty());
+                }
+            }
+        }
+    }
+}
+
+/// Rebalancing works without the `rebalancing` feature, which only adds timing output.
+#[test]
+fn rebalancing_always_on() {
+    // Merge all layers above the bottom one after each partition.
+    let mut q = ConfigurableSimdQuickHeap::<u64, crate::Avx2, MedianOfM<3>, PivotForgetting<1000, 1>>::default();
+    let mut b = std::collections::BinaryHeap::new();
+    for i in 0..20000u64 {
+        let t = i.wrapping_mul(0x9e3779b97f4a7c15) >> 16;
+        q.push(t);
+        b.push(Reverse(t));
+        if i % 2 == 0 {
+            assert_eq!(q.pop(), b.pop().map(|v| v.0));
+            assert!(q.pivots.len() <= 1);
+        }
+    }
+    while let Some(Reverse(t)) = b.pop() {
+        assert_eq!(q.pop(), Some(t));
+    }
+}
+
+/// Push bursts do not leave layers far over the budget, and each push splits at most one layer.
+#[test]
+fn push_splitting_budget() {
+    use std::collections::BinaryHeap;
+    let mut q = PushSplitHeap::<u64, crate::Avx2>::default();
+    let mut b = BinaryHeap::new();
+    let max_len = |q: &PushSplitHeap<u64, crate::Avx2>| {
+        q.buckets[..=q.pivots.len()]
+            .iter()
+            .map(|b| b.len())
+            .max()
+            .unwrap()
+    };
+    let push = |q: &mut PushSplitHeap<u64, crate::Avx2>, b: &mut BinaryHeap<_>, t: u64| {
+        let (layers, max) = (q.pivots.len(), max_len(q));
+        q.push(t);
+        b.push(Reverse(t));
+        assert!(q.pivots.len() <= layers + 1);
+        // Only a layer of at most `max + 1` elements is split.
+        max
+    };
+    for round in 0..10u64 {
+        for i in 0..5000u64 {
+            let t = (round * 5000 + i).wrapping_mul(0x9e3779b97f4a7c15) >> 16;
+            let max = push(&mut q, &mut b, t);
+            assert!(max <= 64 + 4, "{max}");
+        }
+        for _ in 0..2000 {
+            assert_eq!(q.pop(), b.pop().map(|v| v.0));
+        }
+    }
+    for round in 0..10u64 {
+        let ts: Vec<u64> = (0..10000u64)
+            .map(|i| (round * 10000 + i).wrapping_mul(0xd1b54a32d192ed03) >> 16)
+            .collect();
+        let layers = q.pivots.len();
+        q.push_batch(&ts);
+        b.extend(ts.iter().map(|&t| Reverse(t)));
+        assert!(q.pivots.len() <= layers + 1);
+        // The following pushes split the layers that the batch left over the budget.
+        let mut i = 0;
+        while max_len(&q) > 64 + 4 {
+            assert!(i < 10000);
+            let t = (round * 10000 + i).wrapping_mul(0x9e3779b97f4a7c15) >> 16;
+            push(&mut q, &mut b, t);
+            i += 1;
+        }
+    }
+    while let Some(Reverse(t)) = b.pop() {
+        assert_eq!(q.pop(), Some(t));
+    }
+}
+
+/// Push splitting terminates on layers of duplicates, and leaves the heap consistent.
+#[test]
+fn push_splitting_duplicates() {
+    let mut q = PushSplitHeap::<u64, crate::Avx2>::default();
+    for round in 0..10u64 {
+        for _ in 0..1000 {
+            q.push(round);
+        }
+        q.push_batch(&[round; 1000]);
+        assert_eq!(q.pop(), Some(0));
+    }
+    for round in 0..10u64 {
+        let count = if round == 0 { 1990 } else { 2000 };
+        for _ in 0..count {
+            assert_eq!(q.pop(), Some(round));
+        }
+    }
+    assert_eq!(q.pop(), None);
+}
+
+/// The cost model merges the layers left by pops during a burst of pushes.
+#[test]
+fn cost_model_merges_on_pushes() {
+    let mut q =
+        ConfigurableSimdQuickHeap::<u64, crate::Avx2, MedianOfM<3>, CostModelRebalancing>::default(
+        );
+    let mut b = std::collections::BinaryHeap::new();
+    let mut next = 0u64;
+    let mut push = |q: &mut ConfigurableSimdQuickHeap<_, _, _, _>,
+                    b: &mut std::collections::BinaryHeap<_>| {
+        next += 1;
+        let t = next.wrapping_mul(0x9e3779b97f4a7c15) >> 16;
+        q.push(t);
+        b.push(Reverse(t));
+    };
+    for _ in 0..100000 {
+        push(&mut q, &mut b);
+    }
+    for _ in 0..1000 {
+        assert_eq!(q.pop(), b.pop().map(|v| v.0));
+    }
+    let layers = q.pivots.len();
+    assert!(layers > 5, "{layers}");
+    for _ in 0..100000 {
+        push(&mut q, &mut b);
+    }
+    assert!(q.pivots.len() < layers, "{} {layers}", q.pivots.len());
+    while let Some(Reverse(t)) = b.pop() {
+        assert_eq!(q.pop(), Some(t));
+    }
+}
+
+/// A strategy from outside the crate, which merges all layers before every `IT`th push.
+#[derive(Default)]
+struct MergeAll<const IT: u64>;
+impl<T: Copy, const IT: u64> RebalancingStrategy<T> for MergeAll<IT> {
+    fn on_push(&mut self, layers: &mut crate::rebalancing_strategies::Layers<'_, T>) -> bool {
+        if !layers.pushes().is_multiple_of(IT) || layers.count() == 1 {
+            return false;
+        }
+        while layers.count() > 1 {
+            layers.merge(0);
+        }
+        true
+    }
+}
+
+/// Heaps can hold a rebalancing strategy that is chosen at runtime.
+#[test]
+fn boxed_rebalancing() {
+    type BoxedHeap = ConfigurableSimdQuickHeap<
+        u64,
+        crate::Avx2,
+        MedianOfM<3>,
+        Box<dyn RebalancingStrategy<u64>>,
+    >;
+    let strategies = || -> [Box<dyn RebalancingStrategy<u64>>; 4] {
+        [
+            Box::new(NoRebalancing),
+            Box::new(PushSplitting::<64, 4>::default()),
+            Box::new(CostModelRebalancing::<16, 16>::default()),
+            Box::new(MergeAll::<1000>),
+        ]
+    };
+    for (i, strategy) in strategies().into_iter().enumerate() {
+        let mut q = BoxedHeap::with_rebalancing(strategy);
+        let mut b = std::collections::BinaryHeap::new();
+        for round in 0..10u64 {
+            for j in 0..3000u64 {
+                let t = (round * 3000 + j).wrapping_mul(0x9e3779b97f4a7c15) >> 16;
+                q.push(t);
+                b.push(Reverse(t));
+            }
+            let max_len = q.buckets[..=q.pivots.len()].iter().map(|b| b.len()).max();
+            match i {
+                1 => assert!(max_len.unwrap() <= 64 + 4),
+                // The last push merged all layers.
+                3 => assert!(q.pivots.is_empty()),
+                _ => {}
+            }
+            for _ in 0..1000 {
+                assert_eq!(q.pop(), b.pop().map(|v| v.0));
+            }
+        }
+        while let Some(Reverse(t)) = b.pop() {
+            assert_eq!(q.pop(), Some(t));
+        }
+    }
 }